@@ -1,35 +1,224 @@
 use log::{debug, error, info, trace};
+use serde_derive::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::time::{interval, sleep_until, Duration, Instant};
 
-use crate::{Endpoint, EndpointConnection, SensorUpdate, SensorValue};
+use crate::outbox::Outbox;
+use crate::{
+    load_json, save_json, ConnectionState, Endpoint, EndpointConnection, Sensor, SensorCommand,
+    SensorUpdate, SensorValue, WatcherStatus,
+};
+
+// how often outboxes are replayed, lost connections retried and heartbeats sent
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+// how often outboxes are written when changed
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(10);
+
+// how often the cache file is written when values changed
+const CACHE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CachedValue {
+    // latest value received from the watcher
+    pub value: SensorValue,
+
+    // latest value sent to endpoints
+    #[serde(default)]
+    pub sent: SensorValue,
+
+    // unix time of the last change sent, in seconds
+    pub timestamp: u64,
+
+    // direction of the last numeric change sent, for hysteresis
+    #[serde(default)]
+    pub rising: Option<bool>,
+}
+impl CachedValue {
+    pub fn is_significant(&self, sensor: &Sensor, value: &SensorValue) -> bool {
+        // check a new value against the last one sent
+        let (SensorValue::IsF64(sent), SensorValue::IsF64(value)) = (&self.sent, value) else {
+            return self.sent != *value;
+        };
+
+        let delta = value - sent;
+
+        if delta == 0.0 {
+            return false;
+        }
+
+        // absolute deadband
+        if sensor.deadband > 0.0 && delta.abs() < sensor.deadband {
+            return false;
+        }
+
+        // deadband as a percentage of the last value sent
+        if sensor.deadband_percent > 0.0
+            && delta.abs() < sent.abs() * sensor.deadband_percent / 100.0
+        {
+            return false;
+        }
+
+        // reversing direction requires a larger change
+        match self.rising {
+            Some(rising) if rising != (delta > 0.0) => delta.abs() >= sensor.hysteresis,
+            _ => true,
+        }
+    }
+}
+
+struct LastSent {
+    sent_at: Instant,
+    update: SensorUpdate,
+
+    // latest update held back by min_interval
+    pending: Option<SensorUpdate>,
+}
 
 pub struct CacheManager {
     pub enabled: bool,
     pub endpoints: Vec<Endpoint>,
+    pub commands: broadcast::Sender<SensorCommand>,
+    pub states: broadcast::Sender<SensorUpdate>,
+    pub path: String,
 }
 impl CacheManager {
-    pub async fn run(&self, mut rx: mpsc::Receiver<SensorUpdate>) {
-        // init cache
-        let mut cache = HashMap::new();
+    pub async fn run(
+        &self,
+        mut rx: mpsc::Receiver<SensorUpdate>,
+        mut status: mpsc::Receiver<WatcherStatus>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) {
+        // init cache, restoring last values from disk
+        let mut cache = self.load().await;
+        let mut cache_changed = false;
+        let mut cache_interval = interval(CACHE_INTERVAL);
 
         // get endpoints clients if any
         let mut connections = HashMap::new();
 
-        for endpoint in &self.endpoints {
-            let state = Arc::new(Mutex::new(true));
+        // hold updates while endpoints are offline
+        let mut outboxes = HashMap::new();
+        let mut tick_interval = interval(TICK_INTERVAL);
+        let mut outbox_interval = interval(OUTBOX_INTERVAL);
+
+        // latest value shared with template watchers, per sensor
+        let mut states: HashMap<String, SensorValue> = HashMap::new();
+
+        // last update sent to each endpoint, per sensor
+        let mut last_sent: HashMap<&String, HashMap<String, LastSent>> = HashMap::new();
 
+        for endpoint in &self.endpoints {
             connections
                 .entry(&endpoint.name)
-                .or_insert(EndpointConnection {
-                    client: endpoint.get_client(state.clone()).await,
-                    state,
-                });
+                .or_insert(self.connect(endpoint).await);
+            outboxes
+                .entry(&endpoint.name)
+                .or_insert(Outbox::new(endpoint).await);
         }
 
         // wait for senders
         loop {
-            match rx.recv().await {
+            // wake up when the first throttled update is due
+            let next_pending = self.next_pending(&last_sent);
+
+            let update = tokio::select! {
+                update = rx.recv() => update,
+
+                // send throttled updates whose min_interval expired
+                _ = sleep_until(next_pending.unwrap_or_else(Instant::now)), if next_pending.is_some() => {
+                    for endpoint in &self.endpoints {
+                        let connection = connections.get_mut(&endpoint.name).unwrap();
+                        let outbox = outboxes.get_mut(&endpoint.name).unwrap();
+
+                        for last in last_sent.entry(&endpoint.name).or_default().values_mut() {
+                            let Some(update) = &last.pending else {
+                                continue;
+                            };
+
+                            if last.sent_at + self.min_interval(endpoint, &update.sensor) <= Instant::now() {
+                                let update = last.pending.take().unwrap();
+                                self.send(endpoint, update.clone(), connection, outbox).await;
+                                last.sent_at = Instant::now();
+                                last.update = update;
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                _ = tick_interval.tick() => {
+                    for endpoint in &self.endpoints {
+                        let connection = connections.get_mut(&endpoint.name).unwrap();
+                        let outbox = outboxes.get_mut(&endpoint.name).unwrap();
+
+                        // replay queued updates once endpoints are back
+                        if !outbox.is_empty() && self.check_connection(endpoint, connection).await
+                        {
+                            outbox.flush(endpoint, &connection.client).await;
+                        }
+
+                        // republish values unchanged for too long
+                        for (key, last) in last_sent.entry(&endpoint.name).or_default().iter_mut() {
+                            let max_interval = match last.update.sensor.max_interval {
+                                0 => endpoint.max_interval,
+                                max_interval => max_interval,
+                            };
+
+                            if max_interval > 0
+                                && last.sent_at.elapsed() >= Duration::from_millis(max_interval)
+                            {
+                                let update = &mut last.update;
+                                debug!(
+                                    "{} {}: heartbeat => {}",
+                                    &update.device_name, &update.sensor.name, &endpoint.name
+                                );
+                                // current value, even if held back by the deadband
+                                update.last_value = update.value.clone();
+                                if let Some(cached) = cache.get(key) {
+                                    update.value = cached.value.clone();
+                                }
+                                self.send(endpoint, update.clone(), connection, outbox).await;
+                                last.sent_at = Instant::now();
+                            }
+                        }
+                    }
+                    continue;
+                }
+
+                // persist outboxes
+                _ = outbox_interval.tick() => {
+                    for endpoint in &self.endpoints {
+                        outboxes.get_mut(&endpoint.name).unwrap().save(endpoint).await;
+                    }
+                    continue;
+                }
+
+                // flush cache to disk
+                _ = cache_interval.tick(), if cache_changed => {
+                    self.save(&cache).await;
+                    cache_changed = false;
+                    continue;
+                }
+
+                // forward watchers' availability to endpoints
+                Some(status) = status.recv() => {
+                    info!("{} watcher online: {}", &status.name, status.online);
+
+                    for endpoint in &self.endpoints {
+                        let client = connections.get(&endpoint.name).unwrap().client.clone();
+                        endpoint.set_availability(status.clone(), client).await;
+                    }
+                    continue;
+                }
+
+                // shut down like when every sender is gone
+                _ = &mut shutdown => None,
+            };
+
+            match update {
                 Some(update) => {
                     trace!(
                         "{} {}: {:?} received.",
@@ -39,14 +228,27 @@ impl CacheManager {
                     );
 
                     // get cached value for this sensor
-                    let last_value =
-                        cache.get(&self.get_key(&update.device_name, &update.sensor.name).await);
+                    let key = self.get_key(&update.device_name, &update.sensor.name).await;
+
+                    // share changed values with template watchers
+                    if states.get(&key) != Some(&update.value) {
+                        states.insert(key.clone(), update.value.clone());
+                        let _ = self.states.send(update.clone());
+                    }
+                    let cached = cache.get(&key);
+                    let last_value = cached.map(|cached: &CachedValue| &cached.sent);
+
+                    // check if value changed meaningfully from the last one sent
+                    let significant = match cached {
+                        Some(cached) => cached.is_significant(&update.sensor, &update.value),
+                        None => true,
+                    };
 
-                    // check if value changed from the cached one
-                    if last_value != Some(&update.value) {
+                    if significant {
                         for endpoint in &self.endpoints {
                             // clone update and endpoint's client
                             let update1 = SensorUpdate {
+                                platform: update.platform.clone(),
                                 device_name: update.device_name.clone(),
                                 sensor: update.sensor.clone(),
                                 value: update.value.clone(),
@@ -55,43 +257,87 @@ impl CacheManager {
                                     _ => last_value.unwrap().clone(),
                                 },
                             };
-                            let connection = connections.get(&endpoint.name).unwrap();
+                            let connection = connections.get_mut(&endpoint.name).unwrap();
+                            let outbox = outboxes.get_mut(&endpoint.name).unwrap();
+                            let endpoint_sent = last_sent.entry(&endpoint.name).or_default();
 
-                            if !(*connection.state.lock().await) {
-                                error!("Retrying connection to {}...", &endpoint.name);
+                            // coalesce updates within min_interval, keeping the latest
+                            if let Some(last) = endpoint_sent.get_mut(&key) {
+                                let min_interval = self.min_interval(endpoint, &update1.sensor);
 
-                                let state = Arc::new(Mutex::new(true));
-                                connections.insert(
-                                    &endpoint.name,
-                                    EndpointConnection {
-                                        client: endpoint.get_client(state.clone()).await,
-                                        state,
-                                    },
-                                );
+                                if last.sent_at.elapsed() < min_interval {
+                                    trace!(
+                                        "{} {}: {:?} throttled for {}",
+                                        &update1.device_name,
+                                        &update1.sensor.name,
+                                        &update1.value,
+                                        &endpoint.name
+                                    );
+                                    last.pending = Some(SensorUpdate {
+                                        last_value: last.update.value.clone(),
+                                        ..update1
+                                    });
+                                    continue;
+                                }
                             }
 
-                            let client = connections.get(&endpoint.name).unwrap().client.clone();
-
-                            // send data to endpoint
-                            endpoint.run(update1, client).await;
-                            info!(
-                                "{} {}: {:?} => {}",
-                                &update.device_name,
-                                &update.sensor.name,
-                                &update.value,
-                                &endpoint.name
+                            endpoint_sent.insert(
+                                key.clone(),
+                                LastSent {
+                                    sent_at: Instant::now(),
+                                    update: update1.clone(),
+                                    pending: None,
+                                },
                             );
+
+                            self.send(endpoint, update1, connection, outbox).await;
                         }
+                    } else if let Some(cached) = cached {
+                        // values restored from the cache file still need heartbeats
+                        for endpoint in &self.endpoints {
+                            last_sent
+                                .entry(&endpoint.name)
+                                .or_default()
+                                .entry(key.clone())
+                                .or_insert_with(|| LastSent {
+                                    sent_at: get_instant(cached.timestamp),
+                                    update: SensorUpdate {
+                                        value: cached.sent.clone(),
+                                        last_value: cached.sent.clone(),
+                                        ..update.clone()
+                                    },
+                                    pending: None,
+                                });
+                        }
+                    }
 
-                        // check if caching is enabled
-                        if self.enabled {
-                            // Insert new sensor value to cache
-                            let update2 = update.clone();
-                            cache.insert(
-                                self.get_key(&update2.device_name, &update2.sensor.name)
-                                    .await,
-                                update2.value,
-                            );
+                    // check if caching is enabled
+                    if self.enabled {
+                        // Insert new sensor value to cache, tracking the true value
+                        let cached = cache.entry(key).or_insert(CachedValue {
+                            value: SensorValue::None,
+                            sent: SensorValue::None,
+                            timestamp: 0,
+                            rising: None,
+                        });
+
+                        if significant {
+                            cached.rising = match (&cached.sent, &update.value) {
+                                (SensorValue::IsF64(sent), SensorValue::IsF64(value)) => {
+                                    Some(value > sent)
+                                }
+                                _ => None,
+                            };
+                            cached.sent = update.value.clone();
+                            cached.timestamp = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default()
+                                .as_secs();
+                        }
+
+                        if cached.value != update.value {
+                            cached.value = update.value.clone();
+                            cache_changed = true;
                             debug!(
                                 "{} {}: {:?} cache updated.",
                                 &update.device_name, &update.sensor.name, &update.value
@@ -99,12 +345,141 @@ impl CacheManager {
                         }
                     }
                 }
-                None => todo!(),
+                None => {
+                    // close endpoints' connections
+                    for endpoint in &self.endpoints {
+                        let connection = connections.get(&endpoint.name).unwrap();
+                        endpoint
+                            .shutdown(connection.client.clone(), connection.state.clone())
+                            .await;
+                        outboxes
+                            .get_mut(&endpoint.name)
+                            .unwrap()
+                            .save(endpoint)
+                            .await;
+                    }
+
+                    if cache_changed {
+                        self.save(&cache).await;
+                    }
+                    return;
+                }
             };
         }
     }
 
+    async fn send(
+        &self,
+        endpoint: &Endpoint,
+        update: SensorUpdate,
+        connection: &mut EndpointConnection,
+        outbox: &mut Outbox,
+    ) {
+        let connected = self.check_connection(endpoint, connection).await;
+
+        // queue while offline, keeping updates in order
+        if outbox.is_enabled() && (!connected || !outbox.is_empty()) {
+            outbox.push(endpoint, update);
+
+            if connected {
+                outbox.flush(endpoint, &connection.client).await;
+            }
+            return;
+        }
+
+        // send data to endpoint
+        info!(
+            "{} {}: {:?} => {}",
+            &update.device_name, &update.sensor.name, &update.value, &endpoint.name
+        );
+        endpoint.run(update, connection.client.clone()).await;
+    }
+
+    fn min_interval(&self, endpoint: &Endpoint, sensor: &Sensor) -> Duration {
+        // endpoint's min_interval overrides sensor's one
+        Duration::from_millis(match endpoint.min_interval {
+            0 => sensor.min_interval,
+            min_interval => min_interval,
+        })
+    }
+
+    fn next_pending(
+        &self,
+        last_sent: &HashMap<&String, HashMap<String, LastSent>>,
+    ) -> Option<Instant> {
+        // earliest time a throttled update can be sent
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| {
+                last_sent
+                    .get(&endpoint.name)?
+                    .values()
+                    .filter_map(|last| {
+                        let update = last.pending.as_ref()?;
+                        Some(last.sent_at + self.min_interval(endpoint, &update.sensor))
+                    })
+                    .min()
+            })
+            .min()
+    }
+
+    async fn load(&self) -> HashMap<String, CachedValue> {
+        // read the cache file, if any
+        if !self.enabled || self.path.is_empty() {
+            return HashMap::new();
+        }
+
+        load_json(&self.path, "cache file").await
+    }
+
+    async fn save(&self, cache: &HashMap<String, CachedValue>) {
+        // write the cache file atomically
+        if !self.enabled || self.path.is_empty() {
+            return;
+        }
+
+        save_json(&self.path, cache, "cache file").await;
+    }
+
+    async fn connect(&self, endpoint: &Endpoint) -> EndpointConnection {
+        // get a new endpoint client
+        let state = Arc::new(Mutex::new(ConnectionState::Connected));
+
+        EndpointConnection {
+            client: endpoint
+                .get_client(state.clone(), self.commands.clone())
+                .await,
+            state,
+        }
+    }
+
+    async fn check_connection(
+        &self,
+        endpoint: &Endpoint,
+        connection: &mut EndpointConnection,
+    ) -> bool {
+        // reconnect lost endpoints, true when connected
+        if *connection.state.lock().await == ConnectionState::Lost {
+            error!("Retrying connection to {}...", &endpoint.name);
+            *connection = self.connect(endpoint).await;
+        }
+
+        *connection.state.lock().await == ConnectionState::Connected
+    }
+
     async fn get_key(&self, device_name: &String, sensor_name: &String) -> String {
         format!("{}/{}", device_name, sensor_name)
     }
 }
+
+fn get_instant(timestamp: u64) -> Instant {
+    // convert a unix time in seconds to an instant in the past
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    Instant::now()
+        .checked_sub(Duration::from_secs(now.saturating_sub(timestamp)))
+        .unwrap_or_else(Instant::now)
+}
@@ -1,6 +1,9 @@
 #[cfg(feature = "homeassistant")]
 pub mod homeassistant;
 
+#[cfg(feature = "modbus-server")]
+pub mod modbus_server;
+
 #[cfg(feature = "mqtt")]
 pub mod mqtt;
 
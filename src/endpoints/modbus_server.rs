@@ -0,0 +1,238 @@
+use futures::future;
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio_modbus::prelude::{ExceptionCode, Request, Response, SlaveRequest};
+use tokio_modbus::server::{rtu, tcp, Service};
+use tokio_serial::SerialStream;
+
+use crate::registers::{encode, is_bit, register_count, register_kind};
+use crate::serial;
+use crate::{Client, Endpoint, SensorUpdate, SensorValue};
+
+// default modbus tcp port
+const DEFAULT_PORT: u16 = 502;
+
+#[derive(Default)]
+pub struct RegisterMap {
+    holding: HashMap<u16, u16>,
+    input: HashMap<u16, u16>,
+    coils: HashMap<u16, bool>,
+    discrete_inputs: HashMap<u16, bool>,
+}
+impl RegisterMap {
+    fn words(&self, register: &str) -> &HashMap<u16, u16> {
+        match register {
+            "input" => &self.input,
+            _ => &self.holding,
+        }
+    }
+
+    fn words_mut(&mut self, register: &str) -> &mut HashMap<u16, u16> {
+        match register {
+            "input" => &mut self.input,
+            _ => &mut self.holding,
+        }
+    }
+
+    fn bits(&self, register: &str) -> &HashMap<u16, bool> {
+        match register {
+            "discrete_input" => &self.discrete_inputs,
+            _ => &self.coils,
+        }
+    }
+
+    fn bits_mut(&mut self, register: &str) -> &mut HashMap<u16, bool> {
+        match register {
+            "discrete_input" => &mut self.discrete_inputs,
+            _ => &mut self.coils,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RegisterService {
+    slave: u8,
+    registers: Arc<Mutex<RegisterMap>>,
+}
+impl Service for RegisterService {
+    type Request = SlaveRequest<'static>;
+    type Response = Option<Response>;
+    type Exception = ExceptionCode;
+    type Future = future::Ready<Result<Self::Response, Self::Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        // ignore requests for other slaves, 0 answers to any slave id
+        if self.slave != 0 && req.slave != self.slave {
+            return future::ready(Ok(None));
+        }
+
+        let registers = self.registers.lock().unwrap();
+
+        let response = match req.request {
+            Request::ReadHoldingRegisters(address, count) => {
+                read(registers.words("holding"), address, count).map(Response::ReadHoldingRegisters)
+            }
+            Request::ReadInputRegisters(address, count) => {
+                read(registers.words("input"), address, count).map(Response::ReadInputRegisters)
+            }
+            Request::ReadCoils(address, count) => {
+                read(registers.bits("coil"), address, count).map(Response::ReadCoils)
+            }
+            Request::ReadDiscreteInputs(address, count) => {
+                read(registers.bits("discrete_input"), address, count)
+                    .map(Response::ReadDiscreteInputs)
+            }
+
+            // exposed sensors are read only
+            _ => Err(ExceptionCode::IllegalFunction),
+        };
+
+        future::ready(response.map(Some))
+    }
+}
+
+pub async fn get_client(endpoint: Endpoint) -> Client {
+    // map configured sensors to registers, unmapped addresses are invalid
+    let mut registers = RegisterMap::default();
+
+    for sensor in &endpoint.registers {
+        let register = register_kind(&sensor.register);
+
+        for address in sensor.address..sensor.address.saturating_add(register_count(sensor)) {
+            if is_bit(register) {
+                registers.bits_mut(register).insert(address, false);
+            } else {
+                registers.words_mut(register).insert(address, 0);
+            }
+        }
+    }
+
+    let registers = Arc::new(Mutex::new(registers));
+    let service = RegisterService {
+        slave: endpoint.slave,
+        registers: registers.clone(),
+    };
+
+    // serve modbus tcp
+    if !endpoint.host.is_empty() {
+        let endpoint = endpoint.clone();
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_tcp(&endpoint, service).await {
+                error!("{}: modbus tcp server failed: {}", &endpoint.name, e);
+            }
+        });
+    }
+
+    // serve modbus rtu
+    if !endpoint.path.is_empty() {
+        let endpoint = endpoint.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_rtu(&endpoint, service).await {
+                error!("{}: modbus rtu server failed: {}", &endpoint.name, e);
+            }
+        });
+    }
+
+    Client::ModbusServer(registers)
+}
+
+pub async fn send(endpoint: Endpoint, update: SensorUpdate, client: Client) -> bool {
+    let Client::ModbusServer(registers) = client else {
+        return false;
+    };
+
+    let key = format!("{}/{}", &update.device_name, &update.sensor.name);
+    let mut registers = registers.lock().unwrap();
+
+    for sensor in endpoint
+        .registers
+        .iter()
+        .filter(|sensor| sensor.name == key)
+    {
+        let register = register_kind(&sensor.register);
+
+        // store value into mapped registers
+        if is_bit(register) {
+            let bit = match update.value {
+                SensorValue::IsBool(value) => value,
+                SensorValue::IsF64(value) => value != 0.0,
+                _ => false,
+            };
+            registers.bits_mut(register).insert(sensor.address, bit);
+            continue;
+        }
+
+        match encode(sensor, &update.value) {
+            Some(words) => {
+                for (address, word) in (sensor.address..).zip(words) {
+                    registers.words_mut(register).insert(address, word);
+                }
+            }
+            None => error!(
+                "{}: unable to encode {} {:?} as {}",
+                &endpoint.name, &key, &update.value, &sensor.data_type
+            ),
+        }
+    }
+
+    debug!("{}: {} registers updated.", &endpoint.name, &key);
+    true
+}
+
+async fn serve_tcp(endpoint: &Endpoint, service: RegisterService) -> std::io::Result<()> {
+    let port = match endpoint.port {
+        0 => DEFAULT_PORT,
+        port => port,
+    };
+    // hostnames and ipv6 addresses are resolved by the listener
+    let listener = TcpListener::bind((endpoint.host.as_str(), port)).await?;
+    info!(
+        "{}: modbus tcp server listening on {}",
+        &endpoint.name,
+        listener.local_addr()?
+    );
+
+    let server = tcp::Server::new(listener);
+
+    let on_connected = |stream, socket_addr| {
+        let service = service.clone();
+        async move { tcp::accept_tcp_connection(stream, socket_addr, |_| Ok(Some(service.clone()))) }
+    };
+    let on_process_error = |e| error!("modbus tcp server: {}", e);
+
+    server.serve(&on_connected, on_process_error).await
+}
+
+async fn serve_rtu(endpoint: &Endpoint, service: RegisterService) -> std::io::Result<()> {
+    let serial = SerialStream::open(&serial::builder(
+        &endpoint.path,
+        endpoint.baud_rate,
+        &endpoint.parity,
+        endpoint.stop_bits,
+        endpoint.data_bits,
+        &endpoint.flow_control,
+    ))?;
+    info!(
+        "{}: modbus rtu server listening on {}",
+        &endpoint.name, &endpoint.path
+    );
+
+    rtu::Server::new(serial).serve_forever(service).await
+}
+
+fn read<T: Copy>(
+    registers: &HashMap<u16, T>,
+    address: u16,
+    count: u16,
+) -> Result<Vec<T>, ExceptionCode> {
+    // read a range of mapped registers
+    (address..address.saturating_add(count))
+        .map(|address| registers.get(&address).copied())
+        .collect::<Option<Vec<T>>>()
+        .ok_or(ExceptionCode::IllegalDataAddress)
+}
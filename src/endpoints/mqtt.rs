@@ -1,40 +1,312 @@
-use log::{error, trace};
-use rumqttc::{AsyncClient, MqttOptions, QoS, TlsConfiguration};
+use log::{debug, error, info, trace, warn};
+use rumqttc::v5;
+use rumqttc::{
+    AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, Proxy, ProxyAuth, ProxyType, QoS,
+    TlsConfiguration, Transport,
+};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
 use std::{sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{sleep, timeout};
 
-use crate::{read_file, Client, Endpoint, SensorUpdate};
+use crate::{
+    read_file, Client, ConnectionState, Endpoint, Sensor, SensorCommand, SensorUpdate, SensorValue,
+};
 
-pub async fn get_client(endpoint: Endpoint, state: Arc<Mutex<bool>>) -> Client {
+// default home assistant discovery prefix
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+// availability payloads, home assistant defaults
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+// time allowed to flush the offline message on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// maximum packet size in both directions
+const MAX_PACKET_SIZE: usize = 10 * 1024;
+
+#[derive(Clone)]
+enum Connection {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+#[derive(Clone)]
+pub struct MqttClient {
+    connection: Connection,
+
+    // sensors whose discovery config was already published
+    discovered: Arc<std::sync::Mutex<HashSet<String>>>,
+
+    // devices announced as online
+    online: Arc<std::sync::Mutex<HashSet<String>>>,
+
+    // topic aliases assigned on this connection and whether their topic
+    // was already queued, mqtt v5 only
+    aliases: Arc<std::sync::Mutex<HashMap<String, (u16, bool)>>>,
+    alias_max: Arc<AtomicU16>,
+
+    // publishes queued or waiting for the broker's acknowledgement
+    unacked: Arc<AtomicUsize>,
+}
+impl MqttClient {
+    fn new(connection: Connection) -> Self {
+        Self {
+            connection,
+            discovered: Default::default(),
+            online: Default::default(),
+            aliases: Default::default(),
+            alias_max: Default::default(),
+            unacked: Default::default(),
+        }
+    }
+
+    pub fn acknowledged(&self) -> bool {
+        // every publish left the eventloop and was acknowledged
+        self.unacked.load(Ordering::Relaxed) == 0
+    }
+
+    fn track_ack(&self, queued: bool) {
+        match queued {
+            true => self.unacked.fetch_add(1, Ordering::Relaxed),
+            false => self
+                .unacked
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    count.checked_sub(1)
+                })
+                .unwrap_or_default(),
+        };
+    }
+
+    fn try_subscribe(&self, topic: &str) -> Result<(), String> {
+        match &self.connection {
+            Connection::V4(client) => client
+                .try_subscribe(topic, QoS::AtLeastOnce)
+                .map_err(|e| e.to_string()),
+            Connection::V5(client) => client
+                .try_subscribe(topic, v5::mqttbytes::QoS::AtLeastOnce)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    fn try_publish(&self, topic: &str, payload: &'static str) -> Result<(), String> {
+        // retained at least once, without blocking the eventloop
+        match &self.connection {
+            Connection::V4(client) => client
+                .try_publish(topic, QoS::AtLeastOnce, true, payload)
+                .map_err(|e| e.to_string()),
+            Connection::V5(client) => client
+                .try_publish(topic, v5::mqttbytes::QoS::AtLeastOnce, true, payload)
+                .map_err(|e| e.to_string()),
+        }?;
+
+        self.track_ack(true);
+        Ok(())
+    }
+
+    async fn publish(&self, topic: &str, payload: String) -> Result<(), String> {
+        // retained at least once
+        match &self.connection {
+            Connection::V4(client) => client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .map_err(|e| e.to_string()),
+            Connection::V5(client) => client
+                .publish(topic, v5::mqttbytes::QoS::AtLeastOnce, true, payload)
+                .await
+                .map_err(|e| e.to_string()),
+        }?;
+
+        self.track_ack(true);
+        Ok(())
+    }
+
+    async fn publish_state(
+        &self,
+        endpoint: &Endpoint,
+        topic: &str,
+        sensor: &Sensor,
+        payload: String,
+    ) -> Result<(), String> {
+        let client = match &self.connection {
+            Connection::V4(client) => {
+                let qos = rumqttc::qos(endpoint.qos).unwrap_or(QoS::AtLeastOnce);
+
+                client
+                    .publish(topic, qos, endpoint.retain, payload)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                self.track_ack(true);
+                return Ok(());
+            }
+            Connection::V5(client) => client,
+        };
+
+        let qos = v5::mqttbytes::qos(endpoint.qos).unwrap_or(v5::mqttbytes::QoS::AtLeastOnce);
+
+        // stale values age out of retained storage
+        let mut properties = v5::mqttbytes::v5::PublishProperties {
+            message_expiry_interval: match endpoint.message_expiry {
+                0 => None,
+                expiry => Some(expiry),
+            },
+            ..Default::default()
+        };
+
+        for (key, value) in [
+            ("unit", &sensor.unit),
+            ("device_class", &sensor.device_class),
+        ] {
+            if !value.is_empty() {
+                properties
+                    .user_properties
+                    .push((key.to_string(), value.to_string()));
+            }
+        }
+
+        // send the topic with its alias until queued once, then the alias only
+        let alias = match endpoint.topic_aliases {
+            0 => None,
+            _ => {
+                let alias_max = self.alias_max.load(Ordering::Relaxed);
+                let mut aliases = self.aliases.lock().unwrap();
+
+                match aliases.get(topic) {
+                    Some(&(alias, queued)) => Some((alias, queued)),
+                    None if (aliases.len() as u16) < alias_max => {
+                        let alias = aliases.len() as u16 + 1;
+                        aliases.insert(topic.to_string(), (alias, false));
+                        Some((alias, false))
+                    }
+                    None => None,
+                }
+            }
+        };
+
+        properties.topic_alias = alias.map(|(alias, _)| alias);
+
+        let full_topic = match alias {
+            Some((_, true)) => "",
+            _ => topic,
+        };
+
+        client
+            .publish_with_properties(full_topic, qos, endpoint.retain, payload, properties)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.track_ack(true);
+
+        // later publishes are queued after this one, the alias alone is enough
+        if let Some((alias, false)) = alias {
+            if let Some(entry) = self.aliases.lock().unwrap().get_mut(topic) {
+                if entry.0 == alias {
+                    entry.1 = true;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) {
+        match &self.connection {
+            Connection::V4(client) => {
+                let _ = client.disconnect().await;
+            }
+            Connection::V5(client) => {
+                let _ = client.disconnect().await;
+            }
+        }
+    }
+}
+
+pub async fn get_client(
+    endpoint: Endpoint,
+    state: Arc<Mutex<ConnectionState>>,
+    commands: broadcast::Sender<SensorCommand>,
+) -> Client {
     // connect to mqtt broker
-    let mut mqttoptions = MqttOptions::new(&endpoint.name, &endpoint.host, endpoint.port);
-    let max_packet_size = 10 * 1024;
+    *state.lock().await = ConnectionState::Connecting;
+
+    match endpoint.protocol.as_str() {
+        "v5" => get_client_v5(endpoint, state, commands).await,
+        _ => get_client_v4(endpoint, state, commands).await,
+    }
+}
+
+async fn get_client_v4(
+    endpoint: Endpoint,
+    state: Arc<Mutex<ConnectionState>>,
+    commands: broadcast::Sender<SensorCommand>,
+) -> Client {
+    let mut mqttoptions =
+        MqttOptions::new(&endpoint.name, get_broker_addr(&endpoint), endpoint.port);
 
     // set mqtt options
     mqttoptions
-        .set_max_packet_size(max_packet_size, max_packet_size)
+        .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE)
         .set_keep_alive(Duration::from_secs(endpoint.keepalive))
         .set_request_channel_capacity(10)
-        .set_credentials(endpoint.username, endpoint.password);
+        .set_credentials(&endpoint.username, &endpoint.password);
 
-    // check TLS client auth and custom ca settings
-    if !endpoint.client_crt.is_empty() || !endpoint.ca.is_empty() {
-        mqttoptions.set_transport(
-            get_tls_transport(&endpoint.ca, &endpoint.client_crt, &endpoint.client_key).await,
-        );
+    // mark the endpoint offline when the connection drops
+    if !endpoint.availability_topic.is_empty() {
+        mqttoptions.set_last_will(LastWill::new(
+            &endpoint.availability_topic,
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+    }
+
+    // select transport, tls by default when a custom ca or client auth is set
+    mqttoptions.set_transport(get_transport(&endpoint).await);
+
+    // custom websocket handshake headers, e.g. for auth
+    if !endpoint.headers.is_empty() {
+        mqttoptions.set_request_modifier(get_request_modifier(&endpoint));
+    }
+
+    // connect through an http(s) proxy
+    if let Some(proxy) = get_proxy(&endpoint) {
+        mqttoptions.set_proxy(proxy);
     }
 
     // get client and eventloop
     let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let client = MqttClient::new(Connection::V4(client));
+    let client2 = client.clone();
     tokio::spawn(async move {
         // handle coinnection's eventloop
         while let Ok(notification) = eventloop.poll().await {
             trace!("Got notification: {:?}", &notification);
+
+            match notification {
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    *state.lock().await = ConnectionState::Connected;
+                    on_connect(&endpoint, &client2).await;
+                }
+
+                // qos 0 publishes are done once sent, qos 1 and 2 once acknowledged
+                Event::Outgoing(Outgoing::Publish(0)) => client2.track_ack(false),
+                Event::Incoming(Packet::PubAck(_) | Packet::PubComp(_)) => client2.track_ack(false),
+
+                // forward incoming commands to watchers, retained ones are stale
+                Event::Incoming(Packet::Publish(publish)) if !publish.retain => {
+                    on_command(&endpoint, &publish.topic, &publish.payload, &commands)
+                }
+
+                _ => {}
+            }
         }
 
-        // set connection state to false
-        let mut connection = state.lock().await;
-        *connection = false;
+        // set connection state to lost
+        *state.lock().await = ConnectionState::Lost;
 
         error!("Connection to {} lost.", &endpoint.name);
     });
@@ -42,51 +314,625 @@ pub async fn get_client(endpoint: Endpoint, state: Arc<Mutex<bool>>) -> Client {
     Client::MqttClient(client)
 }
 
+async fn get_client_v5(
+    endpoint: Endpoint,
+    state: Arc<Mutex<ConnectionState>>,
+    commands: broadcast::Sender<SensorCommand>,
+) -> Client {
+    let mut mqttoptions =
+        v5::MqttOptions::new(&endpoint.name, get_broker_addr(&endpoint), endpoint.port);
+
+    // set mqtt options
+    mqttoptions
+        .set_max_packet_size(Some(MAX_PACKET_SIZE as u32))
+        .set_keep_alive(Duration::from_secs(endpoint.keepalive))
+        .set_request_channel_capacity(10)
+        .set_credentials(&endpoint.username, &endpoint.password);
+
+    // mark the endpoint offline when the connection drops
+    if !endpoint.availability_topic.is_empty() {
+        mqttoptions.set_last_will(v5::mqttbytes::v5::LastWill::new(
+            &endpoint.availability_topic,
+            OFFLINE,
+            v5::mqttbytes::QoS::AtLeastOnce,
+            true,
+            None,
+        ));
+    }
+
+    // select transport, tls by default when a custom ca or client auth is set
+    mqttoptions.set_transport(get_transport(&endpoint).await);
+
+    // custom websocket handshake headers, e.g. for auth
+    if !endpoint.headers.is_empty() {
+        mqttoptions.set_request_modifier(get_request_modifier(&endpoint));
+    }
+
+    // connect through an http(s) proxy
+    if let Some(proxy) = get_proxy(&endpoint) {
+        mqttoptions.set_proxy(proxy);
+    }
+
+    // get client and eventloop
+    let (client, mut eventloop) = v5::AsyncClient::new(mqttoptions, 10);
+    let client = MqttClient::new(Connection::V5(client));
+    let client2 = client.clone();
+    tokio::spawn(async move {
+        // handle coinnection's eventloop
+        while let Ok(notification) = eventloop.poll().await {
+            trace!("Got notification: {:?}", &notification);
+
+            match notification {
+                v5::Event::Incoming(v5::Incoming::ConnAck(connack)) => {
+                    // aliases are per connection, bounded by the broker's maximum
+                    let broker_max = connack
+                        .properties
+                        .and_then(|properties| properties.topic_alias_max)
+                        .unwrap_or(0);
+
+                    client2
+                        .alias_max
+                        .store(endpoint.topic_aliases.min(broker_max), Ordering::Relaxed);
+
+                    *state.lock().await = ConnectionState::Connected;
+                    on_connect(&endpoint, &client2).await;
+                }
+
+                // qos 0 publishes are done once sent, qos 1 and 2 once acknowledged
+                v5::Event::Outgoing(Outgoing::Publish(0)) => client2.track_ack(false),
+                v5::Event::Incoming(v5::Incoming::PubAck(_) | v5::Incoming::PubComp(_)) => {
+                    client2.track_ack(false)
+                }
+
+                // forward incoming commands to watchers, retained ones are stale
+                v5::Event::Incoming(v5::Incoming::Publish(publish)) if !publish.retain => {
+                    on_command(
+                        &endpoint,
+                        &String::from_utf8_lossy(&publish.topic),
+                        &publish.payload,
+                        &commands,
+                    )
+                }
+
+                _ => {}
+            }
+        }
+
+        // set connection state to lost
+        *state.lock().await = ConnectionState::Lost;
+
+        error!("Connection to {} lost.", &endpoint.name);
+    });
+
+    Client::MqttClient(client)
+}
+
+async fn on_connect(endpoint: &Endpoint, client: &MqttClient) {
+    // subscribe to command topics after each (re)connection
+    if endpoint.commands {
+        let template = get_command_template(endpoint);
+
+        if !template.split('/').any(|level| level == "{device}")
+            || !template.split('/').any(|level| level == "{sensor}")
+        {
+            error!(
+                "{}: commands need {{device}} and {{sensor}} topic levels in {}",
+                &endpoint.name, &template
+            );
+        }
+
+        // any placeholder matches a single level
+        let topic = template
+            .split('/')
+            .map(|level| match level.contains('{') {
+                true => "+",
+                false => level,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if let Err(e) = client.try_subscribe(&topic) {
+            error!(
+                "{}: unable to subscribe to {}: {}",
+                &endpoint.name, &topic, e
+            );
+        }
+    }
+
+    // publish birth message, replacing the last will
+    if !endpoint.availability_topic.is_empty() {
+        if let Err(e) = client.try_publish(&endpoint.availability_topic, ONLINE) {
+            error!(
+                "{}: unable to publish {}: {}",
+                &endpoint.name, &endpoint.availability_topic, e
+            );
+        }
+    }
+}
+
+fn on_command(
+    endpoint: &Endpoint,
+    topic: &str,
+    payload: &[u8],
+    commands: &broadcast::Sender<SensorCommand>,
+) {
+    match get_command(endpoint, topic, payload) {
+        Some(command) => {
+            debug!(
+                "{}: command {}/{} => {:?}",
+                &endpoint.name, &command.device_name, &command.sensor_name, &command.value
+            );
+            let _ = commands.send(command);
+        }
+        None => error!("{}: invalid command topic {}", &endpoint.name, topic),
+    }
+}
+
 pub async fn send(endpoint: Endpoint, update: SensorUpdate, client: Client) -> bool {
+    let json_data = update.get_json().await;
+    let state = json_data["state"].to_string().replace('"', "");
+
     // get sensor update data
-    let post_data = match endpoint.raw {
+    let post_data = match (endpoint.payload.is_empty(), endpoint.raw) {
+        // custom payload layout
+        (false, _) => render(
+            &endpoint.payload,
+            &[
+                ("state", &state),
+                (
+                    "previous_state",
+                    &json_data["attributes"]["previous_state"]
+                        .to_string()
+                        .replace('"', ""),
+                ),
+                ("unit", &update.sensor.unit),
+                ("friendly_name", &update.sensor.friendly_name),
+                ("device_class", &update.sensor.device_class),
+                ("state_class", &update.sensor.state_class),
+                ("device", &update.device_name),
+                ("sensor", &update.sensor.name),
+                ("platform", &update.platform),
+                ("json", &json_data.to_string()),
+            ],
+        ),
         // raw = true, send raw sensor value
-        true => update.get_json().await["state"]
-            .to_string()
-            .replace('"', ""),
+        (true, true) => state,
         // raw = false send json sensor value
-        false => update.get_json().await.to_string(),
+        (true, false) => json_data.to_string(),
     };
 
-    // set mqtt topic, true without prefix.
-    let topic = match endpoint.prefix.is_empty() {
-        true => format!("{}/{}", &update.device_name, &update.sensor.name),
-        false => format!(
-            "{}/{}/{}",
-            &endpoint.prefix, &update.device_name, &update.sensor.name
+    // set mqtt topic
+    let topic = match endpoint.topic.is_empty() {
+        true => get_topic(&endpoint, &update.device_name, &update.sensor.name),
+        false => render(
+            &endpoint.topic,
+            &[
+                ("prefix", &endpoint.prefix),
+                ("device", &update.device_name),
+                ("sensor", &update.sensor.name),
+                ("device_class", &update.sensor.device_class),
+                ("platform", &update.platform),
+            ],
         ),
     };
 
+    let Client::MqttClient(client) = client else {
+        return false;
+    };
+
+    // announce the sensor to home assistant the first time it shows up
+    if endpoint.discovery && !publish_discovery(&endpoint, &update, &topic, &client).await {
+        return false;
+    }
+
+    // mark the device online the first time it shows up
+    if !endpoint.availability_topic.is_empty()
+        && client
+            .online
+            .lock()
+            .unwrap()
+            .insert(update.device_name.clone())
+    {
+        publish_availability(&endpoint, &update.device_name, true, &client).await;
+    }
+
     // spawn publish request
-    match client {
-        Client::MqttClient(client) => client
-            .publish(&topic, QoS::AtLeastOnce, true, post_data)
-            .await
-            .is_ok(), // return a bool
-        _ => false,
+    client
+        .publish_state(&endpoint, &topic, &update.sensor, post_data)
+        .await
+        .is_ok() // return a bool
+}
+
+pub async fn set_availability(
+    endpoint: Endpoint,
+    device_name: String,
+    online: bool,
+    client: Client,
+) -> bool {
+    let Client::MqttClient(client) = client else {
+        return false;
+    };
+
+    if endpoint.availability_topic.is_empty() {
+        return true;
+    }
+
+    // keep track of devices' state, so they are announced again when back
+    match online {
+        true => client.online.lock().unwrap().insert(device_name.clone()),
+        false => client.online.lock().unwrap().remove(&device_name),
+    };
+
+    publish_availability(&endpoint, &device_name, online, &client).await
+}
+
+pub async fn shutdown(endpoint: Endpoint, client: Client, state: Arc<Mutex<ConnectionState>>) {
+    let Client::MqttClient(client) = client else {
+        return;
+    };
+
+    // a clean disconnect discards the last will, say goodbye explicitly
+    if !endpoint.availability_topic.is_empty() {
+        let _ = client
+            .publish(&endpoint.availability_topic, OFFLINE.to_string())
+            .await;
+    }
+    client.disconnect().await;
+
+    // wait for the eventloop to flush pending messages
+    let flushed = timeout(SHUTDOWN_TIMEOUT, async {
+        while *state.lock().await != ConnectionState::Lost {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+
+    match flushed {
+        Ok(_) => info!("{}: disconnected.", &endpoint.name),
+        Err(_) => error!("{}: timeout while disconnecting.", &endpoint.name),
+    }
+}
+
+async fn publish_availability(
+    endpoint: &Endpoint,
+    device_name: &str,
+    online: bool,
+    client: &MqttClient,
+) -> bool {
+    let topic = get_availability_topic(endpoint, device_name);
+    let payload = match online {
+        true => ONLINE,
+        false => OFFLINE,
+    };
+    debug!("{}: {} => {}", &endpoint.name, &topic, payload);
+
+    match client.publish(&topic, payload.to_string()).await {
+        Ok(_) => true,
+        Err(e) => {
+            error!("{}: unable to publish {}: {}", &endpoint.name, &topic, e);
+            false
+        }
+    }
+}
+
+async fn publish_discovery(
+    endpoint: &Endpoint,
+    update: &SensorUpdate,
+    state_topic: &str,
+    client: &MqttClient,
+) -> bool {
+    let key = format!("{}/{}", &update.device_name, &update.sensor.name);
+
+    if !client.discovered.lock().unwrap().insert(key.clone()) {
+        return true;
+    }
+
+    let (topic, payload) = get_discovery(endpoint, update, state_topic);
+    debug!(
+        "{}: {} discovery config => {}",
+        &endpoint.name, &key, &topic
+    );
+
+    match client.publish(&topic, payload.to_string()).await {
+        Ok(_) => true,
+        Err(e) => {
+            // retry on next update
+            error!("{}: unable to publish {}: {}", &endpoint.name, &topic, e);
+            client.discovered.lock().unwrap().remove(&key);
+            false
+        }
+    }
+}
+
+fn get_discovery(
+    endpoint: &Endpoint,
+    update: &SensorUpdate,
+    state_topic: &str,
+) -> (String, serde_json::Value) {
+    // booleans are binary sensors, anything else a plain sensor
+    let component = match update.value {
+        SensorValue::IsBool(_) => "binary_sensor",
+        _ => "sensor",
+    };
+
+    let node_id = get_object_id(&update.device_name);
+    let object_id = get_object_id(&update.sensor.name);
+    let sensor = &update.sensor;
+
+    let mut payload = json!({
+        "name": match sensor.friendly_name.is_empty() {
+            true => &sensor.name,
+            false => &sensor.friendly_name,
+        },
+        "unique_id": format!("{}_{}", &node_id, &object_id),
+        "state_topic": state_topic,
+        "device": {
+            "identifiers": [&node_id],
+            "name": &update.device_name,
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+
+    // json payloads carry the state and the attributes
+    if !endpoint.value_template.is_empty() {
+        payload["value_template"] = endpoint.value_template.as_str().into();
+    } else if !endpoint.payload.is_empty() {
+        warn!(
+            "{}: custom payload without value_template, {} shows the whole payload",
+            &endpoint.name, &update.sensor.name
+        );
+    } else if !endpoint.raw {
+        payload["value_template"] = "{{ value_json.state }}".into();
+        payload["json_attributes_topic"] = state_topic.into();
+        payload["json_attributes_template"] = "{{ value_json.attributes | tojson }}".into();
+    }
+
+    // both rszurro and the watcher must be alive
+    if !endpoint.availability_topic.is_empty() {
+        payload["availability"] = json!([
+            { "topic": &endpoint.availability_topic },
+            { "topic": get_availability_topic(endpoint, &update.device_name) },
+        ]);
+        payload["availability_mode"] = "all".into();
+    }
+
+    if component == "binary_sensor" {
+        payload["payload_on"] = "on".into();
+        payload["payload_off"] = "off".into();
+    }
+
+    for (key, value) in [
+        ("unit_of_measurement", &sensor.unit),
+        ("device_class", &sensor.device_class),
+        ("state_class", &sensor.state_class),
+    ] {
+        if !value.is_empty() {
+            payload[key] = value.as_str().into();
+        }
     }
+
+    let prefix = match endpoint.discovery_prefix.is_empty() {
+        true => DISCOVERY_PREFIX,
+        false => &endpoint.discovery_prefix,
+    };
+    let topic = format!("{}/{}/{}/{}/config", prefix, component, node_id, object_id);
+
+    (topic, payload)
 }
 
-async fn get_tls_transport(
-    ca: &String,
-    client_crt: &String,
-    client_key: &String,
-) -> rumqttc::Transport {
-    // Get a Tls Transport
-    rumqttc::Transport::Tls(TlsConfiguration::Simple {
-        ca: match ca.is_empty() {
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    // replace {placeholders} with their values in a single pass, so values
+    // looking like placeholders are left alone
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let placeholder = rest[1..].find('}').and_then(|end| {
+            let key = &rest[1..=end];
+            values
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| (end, *value))
+        });
+
+        match placeholder {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 2..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+fn get_availability_topic(endpoint: &Endpoint, device_name: &str) -> String {
+    // per watcher availability below the endpoint's one
+    format!("{}/{}", &endpoint.availability_topic, device_name)
+}
+
+fn get_object_id(name: &str) -> String {
+    // home assistant ids only allow [a-zA-Z0-9_-]
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+fn get_topic(endpoint: &Endpoint, device_name: &str, sensor_name: &str) -> String {
+    // set mqtt topic, true without prefix.
+    match endpoint.prefix.is_empty() {
+        true => format!("{}/{}", device_name, sensor_name),
+        false => format!("{}/{}/{}", &endpoint.prefix, device_name, sensor_name),
+    }
+}
+
+fn get_command_template(endpoint: &Endpoint) -> String {
+    // state topic followed by /set, only the prefix is known in advance
+    let topic = match endpoint.topic.is_empty() {
+        true => get_topic(endpoint, "{device}", "{sensor}"),
+        false => render(&endpoint.topic, &[("prefix", &endpoint.prefix)]),
+    };
+
+    topic + "/set"
+}
+
+fn get_command(endpoint: &Endpoint, topic: &str, payload: &[u8]) -> Option<SensorCommand> {
+    // match the topic against the command template level by level
+    let template = get_command_template(endpoint);
+
+    if template.split('/').count() != topic.split('/').count() {
+        return None;
+    }
+
+    let (mut device_name, mut sensor_name) = (None, None);
+
+    for (expected, level) in template.split('/').zip(topic.split('/')) {
+        match expected {
+            "{device}" => device_name = Some(level),
+            "{sensor}" => sensor_name = Some(level),
+            expected if expected.contains('{') => {}
+            expected if expected != level => return None,
+            _ => {}
+        }
+    }
+
+    let (device_name, sensor_name) = (device_name?, sensor_name?);
+
+    Some(SensorCommand {
+        device_name: device_name.to_string(),
+        sensor_name: sensor_name.to_string(),
+        value: SensorValue::from_payload(&String::from_utf8_lossy(payload)),
+        issued: std::time::Instant::now(),
+    })
+}
+
+fn get_broker_addr(endpoint: &Endpoint) -> String {
+    // websockets take the broker's url instead of its host
+    let path = match endpoint.ws_path.is_empty() {
+        true => "/mqtt",
+        false => &endpoint.ws_path,
+    };
+
+    match endpoint.transport.as_str() {
+        "ws" | "wss" => format!(
+            "{}://{}:{}{}",
+            &endpoint.transport, &endpoint.host, endpoint.port, path
+        ),
+        _ => endpoint.host.clone(),
+    }
+}
+
+async fn get_transport(endpoint: &Endpoint) -> Transport {
+    // get the configured transport
+    match endpoint.transport.as_str() {
+        "tcp" => Transport::Tcp,
+        "tls" => Transport::Tls(get_tls_config(endpoint).await),
+        "ws" => Transport::Ws,
+        "wss" => Transport::Wss(get_tls_config(endpoint).await),
+        _ => match !endpoint.client_crt.is_empty() || !endpoint.ca.is_empty() {
+            true => Transport::Tls(get_tls_config(endpoint).await),
+            false => Transport::Tcp,
+        },
+    }
+}
+
+fn get_request_modifier(
+    endpoint: &Endpoint,
+) -> impl Fn(http::Request<()>) -> std::future::Ready<http::Request<()>> + Send + Sync + 'static {
+    // add custom headers to the websocket handshake
+    let mut headers = http::HeaderMap::new();
+
+    for (name, value) in &endpoint.headers {
+        match (
+            http::HeaderName::try_from(name),
+            http::HeaderValue::try_from(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => error!("{}: invalid header {}", &endpoint.name, name),
+        }
+    }
+
+    move |mut request| {
+        request.headers_mut().extend(headers.clone());
+        std::future::ready(request)
+    }
+}
+
+fn get_proxy(endpoint: &Endpoint) -> Option<Proxy> {
+    // parse http(s)://[username:password@]host:port
+    if endpoint.proxy.is_empty() {
+        return None;
+    }
+
+    let (ty, address) = match endpoint.proxy.split_once("://") {
+        Some(("https", address)) => (ProxyType::Https(TlsConfiguration::default()), address),
+        Some((_, address)) => (ProxyType::Http, address),
+        None => (ProxyType::Http, endpoint.proxy.as_str()),
+    };
+
+    let (auth, address) = match address.rsplit_once('@') {
+        Some((credentials, address)) => {
+            let (username, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+            let auth = ProxyAuth::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            };
+            (auth, address)
+        }
+        None => (ProxyAuth::None, address),
+    };
+
+    let address = address.trim_end_matches('/');
+    let (addr, port) = match address.rsplit_once(':') {
+        Some((addr, port)) => match port.parse() {
+            Ok(port) => (addr, port),
+            Err(_) => {
+                error!("{}: invalid proxy port {}", &endpoint.name, port);
+                return None;
+            }
+        },
+        None => (address, 8080),
+    };
+
+    Some(Proxy {
+        ty,
+        auth,
+        addr: addr.to_string(),
+        port,
+    })
+}
+
+async fn get_tls_config(endpoint: &Endpoint) -> TlsConfiguration {
+    // system's root certificates without custom ca or client auth
+    if endpoint.ca.is_empty() && endpoint.client_crt.is_empty() {
+        return TlsConfiguration::default();
+    }
+
+    TlsConfiguration::Simple {
+        ca: match endpoint.ca.is_empty() {
             true => vec![],
-            false => read_file(ca).await,
+            false => read_file(&endpoint.ca).await,
         },
         alpn: None,
-        client_auth: match client_crt.is_empty() {
+        client_auth: match endpoint.client_crt.is_empty() {
             true => None,
-            false => Some((read_file(client_crt).await, read_file(client_key).await)),
+            false => Some((
+                read_file(&endpoint.client_crt).await,
+                read_file(&endpoint.client_key).await,
+            )),
         },
-    })
+    }
 }
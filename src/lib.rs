@@ -1,20 +1,39 @@
 pub mod cache_manager;
 pub mod endpoints;
+pub mod outbox;
+pub mod profiles;
+pub mod stages;
 pub mod watchers;
 
+#[cfg(feature = "modbus-rtu")]
+pub mod scanner;
+
+#[cfg(any(
+    feature = "modbus-rtu",
+    feature = "modbus-tcp",
+    feature = "modbus-server"
+))]
+pub mod registers;
+
+#[cfg(any(feature = "modbus-rtu", feature = "modbus-server"))]
+pub mod serial;
+
 pub use cache_manager::CacheManager;
+pub use stages::Pipeline;
 
-use log::error;
+use log::{debug, error, info};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::{io::AsyncReadExt, sync::mpsc, sync::Mutex};
+use tokio::{io::AsyncReadExt, sync::broadcast, sync::mpsc, sync::Mutex};
 
 #[derive(clap::Parser)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
     // Sets a custom config file
     #[arg(value_name = "FILE", required = true)]
-    pub config: String,
+    pub config: Option<String>,
 
     // verbosity level
     #[arg(short, long, action = clap::ArgAction::Count)]
@@ -23,12 +42,31 @@ pub struct Cli {
     // disable caching
     #[arg(long, action)]
     pub nocache: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Scan a modbus rtu bus for slaves and readable registers
+    #[cfg(feature = "modbus-rtu")]
+    Scan(scanner::ScanArgs),
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct ConfigFile {
     pub endpoints: Vec<Endpoint>,
     pub watchers: Vec<Watcher>,
+
+    #[serde(default)]
+    pub profiles: String,
+
+    #[serde(default)]
+    pub cache: String,
+
+    #[serde(default)]
+    pub integration_path: String,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -74,20 +112,114 @@ pub struct Endpoint {
 
     #[serde(default)]
     pub client_key: String,
+
+    #[serde(default)]
+    pub commands: bool,
+
+    #[serde(default)]
+    pub discovery: bool,
+
+    #[serde(default)]
+    pub discovery_prefix: String,
+
+    #[serde(default)]
+    pub availability_topic: String,
+
+    #[serde(default = "endpoint_default_qos")]
+    pub qos: u8,
+
+    #[serde(default = "endpoint_default_retain")]
+    pub retain: bool,
+
+    #[serde(default)]
+    pub topic: String,
+
+    #[serde(default)]
+    pub payload: String,
+
+    #[serde(default)]
+    pub value_template: String,
+
+    #[serde(default)]
+    pub protocol: String,
+
+    #[serde(default)]
+    pub message_expiry: u32,
+
+    #[serde(default)]
+    pub topic_aliases: u16,
+
+    #[serde(default)]
+    pub transport: String,
+
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+
+    #[serde(default)]
+    pub ws_path: String,
+
+    #[serde(default)]
+    pub proxy: String,
+
+    #[serde(default)]
+    pub outbox: usize,
+
+    #[serde(default)]
+    pub outbox_policy: String,
+
+    #[serde(default)]
+    pub outbox_path: String,
+
+    #[serde(default)]
+    pub max_interval: u64,
+
+    #[serde(default)]
+    pub min_interval: u64,
+
+    #[serde(default)]
+    pub path: String,
+
+    #[serde(default)]
+    pub baud_rate: u32,
+
+    #[serde(default)]
+    pub parity: String,
+
+    #[serde(default)]
+    pub stop_bits: u8,
+
+    #[serde(default)]
+    pub data_bits: u8,
+
+    #[serde(default)]
+    pub flow_control: String,
+
+    #[serde(default)]
+    pub slave: u8,
+
+    #[serde(default)]
+    pub registers: Vec<Sensor>,
 }
 impl Endpoint {
-    pub async fn get_client(&self, state: Arc<Mutex<bool>>) -> Client {
+    pub async fn get_client(
+        &self,
+        state: Arc<Mutex<ConnectionState>>,
+        commands: broadcast::Sender<SensorCommand>,
+    ) -> Client {
         let endpoint = self.clone();
 
         match endpoint.platform.as_str() {
             #[cfg(feature = "mqtt")]
-            "mqtt" => endpoints::mqtt::get_client(endpoint, state).await,
+            "mqtt" => endpoints::mqtt::get_client(endpoint, state, commands).await,
+
+            #[cfg(feature = "modbus-server")]
+            "modbus_server" => endpoints::modbus_server::get_client(endpoint).await,
 
             _ => Client::None,
         }
     }
 
-    pub async fn run(&self, update: SensorUpdate, client: Client) -> tokio::task::JoinHandle<()> {
+    pub async fn run(&self, update: SensorUpdate, client: Client) -> tokio::task::JoinHandle<bool> {
         // initialize endpoint
         let endpoint = self.clone();
 
@@ -102,16 +234,53 @@ impl Endpoint {
                 #[cfg(feature = "mqtt")]
                 "mqtt" => endpoints::mqtt::send(endpoint, update, client).await,
 
+                #[cfg(feature = "modbus-server")]
+                "modbus_server" => endpoints::modbus_server::send(endpoint, update, client).await,
+
                 _ => {
                     error!("unsupported endpoint platform: {}", &endpoint.platform);
                     false
                 }
-            };
+            }
         })
     }
+
+    pub async fn set_availability(&self, status: WatcherStatus, client: Client) {
+        // announce a watcher's availability, where supported
+        let endpoint = self.clone();
+
+        match endpoint.platform.as_str() {
+            #[cfg(feature = "mqtt")]
+            "mqtt" => {
+                for device_name in status.devices {
+                    endpoints::mqtt::set_availability(
+                        endpoint.clone(),
+                        device_name,
+                        status.online,
+                        client.clone(),
+                    )
+                    .await;
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    pub async fn shutdown(&self, client: Client, state: Arc<Mutex<ConnectionState>>) {
+        // gracefully close endpoint's connection
+        let endpoint = self.clone();
+
+        match endpoint.platform.as_str() {
+            #[cfg(feature = "mqtt")]
+            "mqtt" => endpoints::mqtt::shutdown(endpoint, client, state).await,
+
+            _ => {}
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Default, Clone)]
 pub struct Watcher {
     pub name: String,
     pub platform: String,
@@ -125,6 +294,15 @@ pub struct Watcher {
     #[serde(default)]
     pub host: String,
 
+    #[serde(default)]
+    pub port: u16,
+
+    #[serde(default)]
+    pub username: String,
+
+    #[serde(default)]
+    pub password: String,
+
     #[serde(default)]
     pub chip: String,
 
@@ -134,9 +312,27 @@ pub struct Watcher {
     #[serde(default)]
     pub baud_rate: u32,
 
+    #[serde(default)]
+    pub parity: String,
+
+    #[serde(default)]
+    pub stop_bits: u8,
+
+    #[serde(default)]
+    pub data_bits: u8,
+
+    #[serde(default)]
+    pub flow_control: String,
+
     #[serde(default)]
     pub scan_interval: u64,
 
+    #[serde(default)]
+    pub cycle_interval: u64,
+
+    #[serde(default)]
+    pub frame_delay: u64,
+
     #[serde(default)]
     pub timeout: u64,
 
@@ -147,11 +343,37 @@ pub struct Watcher {
     pub temperature_unit: String,
 }
 impl Watcher {
-    pub async fn run(&self, tx: mpsc::Sender<SensorUpdate>) -> tokio::task::JoinHandle<()> {
+    pub fn devices(&self) -> Vec<String> {
+        // gpio sensors belong to their chip, modbus ones to their slave
+        match self.platform.as_str() {
+            "gpio" => vec![self.chip.clone()],
+            "modbus_rtu" | "modbus_tcp" => {
+                let mut devices: Vec<String> = self
+                    .slaves
+                    .iter()
+                    .map(|slave| slave.device_name(self))
+                    .collect();
+                devices.sort();
+                devices.dedup();
+                devices
+            }
+            _ => vec![self.name.clone()],
+        }
+    }
+
+    pub async fn run(
+        &self,
+        tx: mpsc::Sender<SensorUpdate>,
+        commands: broadcast::Sender<SensorCommand>,
+        states: broadcast::Sender<SensorUpdate>,
+        status: mpsc::Sender<WatcherStatus>,
+    ) -> tokio::task::JoinHandle<()> {
         // run a watcher
         let watcher = self.clone();
+        let name = watcher.name.clone();
+        let devices = watcher.devices();
 
-        match watcher.platform.as_str() {
+        let handle = match watcher.platform.as_str() {
             #[cfg(feature = "gpio")]
             "gpio" => tokio::spawn(async move { watchers::gpio::run(watcher, tx).await.unwrap() }),
 
@@ -162,7 +384,22 @@ impl Watcher {
 
             #[cfg(feature = "modbus-rtu")]
             "modbus_rtu" => {
-                tokio::spawn(async move { watchers::modbus_rtu::run(watcher, tx).await.unwrap() })
+                let commands = commands.subscribe();
+                tokio::spawn(async move {
+                    watchers::modbus_rtu::run(watcher, tx, commands)
+                        .await
+                        .unwrap()
+                })
+            }
+
+            #[cfg(feature = "modbus-tcp")]
+            "modbus_tcp" => {
+                let commands = commands.subscribe();
+                tokio::spawn(async move {
+                    watchers::modbus_tcp::run(watcher, tx, commands)
+                        .await
+                        .unwrap()
+                })
             }
 
             #[cfg(feature = "sysinfo")]
@@ -173,18 +410,63 @@ impl Watcher {
             #[cfg(feature = "icmp")]
             "icmp" => tokio::spawn(async move { watchers::icmp::run(watcher, tx).await.unwrap() }),
 
-            &_ => todo!(),
-        }
+            #[cfg(feature = "mqtt")]
+            "mqtt" => tokio::spawn(async move { watchers::mqtt::run(watcher, tx).await.unwrap() }),
+
+            #[cfg(feature = "template")]
+            "template" => {
+                let states = states.subscribe();
+                tokio::spawn(
+                    async move { watchers::template::run(watcher, tx, states).await.unwrap() },
+                )
+            }
+
+            // platform unknown or not compiled in
+            platform => {
+                error!("{}: unsupported watcher platform {}", &name, platform);
+                tokio::spawn(async {})
+            }
+        };
+
+        // report the watcher as unavailable when its task ends
+        tokio::spawn(async move {
+            if let Err(e) = handle.await {
+                error!("{} watcher failed: {}", &name, e);
+            }
+
+            let _ = status
+                .send(WatcherStatus {
+                    name,
+                    devices,
+                    online: false,
+                })
+                .await;
+        })
     }
 }
 
 #[derive(Deserialize, Serialize, Default, Clone)]
 pub struct Slave {
+    #[serde(default)]
+    pub name: String,
+
     #[serde(default)]
     pub sensors: Vec<Sensor>,
 
     #[serde(default)]
     pub address: u8,
+
+    #[serde(default)]
+    pub profile: String,
+}
+impl Slave {
+    pub fn device_name(&self, watcher: &Watcher) -> String {
+        // slaves sharing a profile need their own device
+        match self.name.is_empty() {
+            true => watcher.name.clone(),
+            false => self.name.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
@@ -200,9 +482,99 @@ pub struct Sensor {
     #[serde(default)]
     pub address: u16,
 
+    #[serde(default)]
+    pub register: String,
+
+    #[serde(default)]
+    pub writable: bool,
+
     #[serde(default = "sensor_default_accuracy")]
     pub accuracy: f64,
 
+    #[serde(default)]
+    pub offset: f64,
+
+    #[serde(default)]
+    pub data_type: String,
+
+    #[serde(default)]
+    pub count: u16,
+
+    #[serde(default)]
+    pub byte_order: String,
+
+    #[serde(default)]
+    pub word_order: String,
+
+    #[serde(default)]
+    pub topic: String,
+
+    #[serde(default)]
+    pub value_path: String,
+
+    #[serde(default)]
+    pub deadband: f64,
+
+    #[serde(default)]
+    pub deadband_percent: f64,
+
+    #[serde(default)]
+    pub hysteresis: f64,
+
+    #[serde(default)]
+    pub max_interval: u64,
+
+    #[serde(default)]
+    pub min_interval: u64,
+
+    #[serde(default)]
+    pub aggregate: String,
+
+    #[serde(default)]
+    pub aggregate_window: u64,
+
+    #[serde(default)]
+    pub aggregate_count: usize,
+
+    #[serde(default = "sensor_default_precision")]
+    pub aggregate_precision: u8,
+
+    #[serde(default)]
+    pub template: String,
+
+    #[serde(default = "sensor_default_precision")]
+    pub template_precision: u8,
+
+    #[serde(default)]
+    pub integration: String,
+
+    #[serde(default)]
+    pub integration_prefix: String,
+
+    #[serde(default = "sensor_default_time_unit")]
+    pub integration_time: String,
+
+    #[serde(default)]
+    pub integration_reset: String,
+
+    #[serde(default)]
+    pub integration_sign: String,
+
+    #[serde(default = "sensor_default_integration_precision")]
+    pub integration_precision: u8,
+
+    #[serde(default)]
+    pub derivative: String,
+
+    #[serde(default = "sensor_default_time_unit")]
+    pub derivative_time: String,
+
+    #[serde(default)]
+    pub derivative_window: u64,
+
+    #[serde(default = "sensor_default_precision")]
+    pub derivative_precision: u8,
+
     #[serde(default)]
     pub unit: String,
 
@@ -224,12 +596,14 @@ impl Sensor {
             state_class: "".to_string(),
             device_class: "".to_string(),
             debounce_delay: 0,
+            ..Default::default()
         }
     }
 }
 
-#[derive(Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct SensorUpdate {
+    pub platform: String,
     pub device_name: String,
     pub sensor: Sensor,
     pub value: SensorValue,
@@ -285,13 +659,20 @@ impl SensorUpdate {
     async fn zero_decimal(&self, float_value: f64) -> bool {
         let decimal = float_value.fract();
 
-        decimal.abs() < std::f64::EPSILON
+        decimal.abs() < f64::EPSILON
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Lost,
+}
+
 pub struct EndpointConnection {
     client: Client,
-    state: Arc<Mutex<bool>>,
+    state: Arc<Mutex<ConnectionState>>,
 }
 
 #[derive(Clone, Default)]
@@ -300,55 +681,177 @@ pub enum Client {
     None,
 
     #[cfg(feature = "mqtt")]
-    MqttClient(rumqttc::AsyncClient),
+    MqttClient(endpoints::mqtt::MqttClient),
+
+    #[cfg(feature = "modbus-server")]
+    ModbusServer(Arc<std::sync::Mutex<endpoints::modbus_server::RegisterMap>>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl Client {
+    pub fn acknowledged(&self) -> bool {
+        // delivery confirmed, where the endpoint provides one
+        match self {
+            #[cfg(feature = "mqtt")]
+            Client::MqttClient(client) => client.acknowledged(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WatcherStatus {
+    pub name: String,
+
+    // device names the watcher publishes its sensors under
+    pub devices: Vec<String>,
+    pub online: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct SensorCommand {
+    pub device_name: String,
+    pub sensor_name: String,
+    pub value: SensorValue,
+    pub issued: std::time::Instant,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
 pub enum SensorValue {
     IsBool(bool),
     IsF64(f64),
     IsString(String),
+    #[default]
     None,
 }
+impl SensorValue {
+    pub fn from_payload(payload: &str) -> Self {
+        // guess value type from a text payload
+        let payload = payload.trim().trim_matches('"');
+
+        match payload.to_lowercase().as_str() {
+            "on" | "true" => SensorValue::IsBool(true),
+            "off" | "false" => SensorValue::IsBool(false),
+            _ => match payload.parse::<f64>() {
+                Ok(value) => SensorValue::IsF64(value),
+                Err(_) => SensorValue::IsString(payload.to_string()),
+            },
+        }
+    }
+}
 
 fn sensor_default_accuracy() -> f64 {
     1.0
 }
 
+fn sensor_default_time_unit() -> String {
+    "h".to_string()
+}
+
+fn sensor_default_precision() -> u8 {
+    2
+}
+
+fn sensor_default_integration_precision() -> u8 {
+    3
+}
+
+fn endpoint_default_qos() -> u8 {
+    1
+}
+
+fn endpoint_default_retain() -> bool {
+    true
+}
+
 pub async fn update_sensor(
     tx: &mpsc::Sender<SensorUpdate>,
+    platform: &str,
     device_name: &String,
     sensor: &Sensor,
     value: SensorValue,
 ) {
     // instantiating SensorUpdate
     let update = SensorUpdate {
+        platform: platform.to_string(),
         device_name: device_name.to_string(),
         sensor: sensor.clone(),
         value,
         last_value: SensorValue::None,
     };
 
-    // send sensor update to cache channel
-    tx.send(update).await.unwrap();
+    // send sensor update to cache channel, closed while shutting down
+    if tx.send(update).await.is_err() {
+        debug!("{} {}: pipeline stopped", device_name, &sensor.name);
+    }
 }
 
 pub fn update_sensor_sync(
     tx: &mpsc::Sender<SensorUpdate>,
+    platform: &str,
     device_name: &String,
     sensor: &Sensor,
     value: SensorValue,
 ) {
     // instantiating SensorUpdate
     let update = SensorUpdate {
+        platform: platform.to_string(),
         device_name: device_name.to_string(),
         sensor: sensor.clone(),
         value,
         last_value: SensorValue::None,
     };
 
-    // send sensor update to cache channel
-    tx.blocking_send(update).unwrap();
+    // send sensor update to cache channel, closed while shutting down
+    if tx.blocking_send(update).is_err() {
+        debug!("{} {}: pipeline stopped", device_name, &sensor.name);
+    }
+}
+
+pub async fn load_json<T: serde::de::DeserializeOwned + Default>(path: &str, what: &str) -> T {
+    // restore state saved by a previous run, if any
+    if path.is_empty() {
+        return T::default();
+    }
+
+    match tokio::fs::read(path).await {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(value) => {
+                info!("{} loaded from {}", what, path);
+                value
+            }
+            Err(e) => {
+                error!("unable to parse {} {}: {}", what, path, e);
+                T::default()
+            }
+        },
+        Err(e) => {
+            debug!("unable to read {} {}: {}", what, path, e);
+            T::default()
+        }
+    }
+}
+
+pub async fn save_json<T: serde::Serialize>(path: &str, value: &T, what: &str) -> bool {
+    // write to a temporary file first, a crash never leaves a truncated one
+    let temp_path = format!("{}.tmp", path);
+    let data = serde_json::to_vec(value).unwrap();
+
+    match tokio::fs::write(&temp_path, data).await {
+        Ok(_) => match tokio::fs::rename(&temp_path, path).await {
+            Ok(_) => {
+                debug!("{} saved to {}", what, path);
+                true
+            }
+            Err(e) => {
+                error!("unable to write {} {}: {}", what, path, e);
+                false
+            }
+        },
+        Err(e) => {
+            error!("unable to write {} {}: {}", what, &temp_path, e);
+            false
+        }
+    }
 }
 
 pub async fn read_file(filename: &String) -> Vec<u8> {
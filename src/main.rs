@@ -1,8 +1,11 @@
 use clap::Parser;
 use log::{info, LevelFilter};
-use tokio::sync::mpsc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
-use rszurro::{CacheManager, Cli, ConfigFile};
+use rszurro::{profiles, CacheManager, Cli, ConfigFile, Pipeline};
+#[cfg(feature = "modbus-rtu")]
+use rszurro::{scanner, Command};
 
 #[tokio::main()]
 async fn main() {
@@ -14,12 +17,6 @@ async fn main() {
         println!("- rszurro v{} -", env!("CARGO_PKG_VERSION"));
     }
 
-    // read configuration file
-    let rszurro = {
-        let configuration = std::fs::read_to_string(&cli.config).unwrap();
-        serde_yaml::from_str::<ConfigFile>(&configuration).unwrap()
-    };
-
     // init logger
     env_logger::Builder::new()
         .filter_level(match cli.verbose {
@@ -32,20 +29,52 @@ async fn main() {
         })
         .init();
 
-    // init channel
+    // run subcommands
+    match cli.command {
+        #[cfg(feature = "modbus-rtu")]
+        Some(Command::Scan(args)) => return scanner::run(args).await,
+        _ => {}
+    }
+
+    // read configuration file
+    let rszurro = {
+        let configuration = std::fs::read_to_string(cli.config.unwrap()).unwrap();
+        let mut configuration = serde_yaml::from_str(&configuration).unwrap();
+
+        // expand device profiles
+        profiles::resolve(&mut configuration).unwrap();
+        serde_yaml::from_value::<ConfigFile>(configuration).unwrap()
+    };
+
+    // init channels
     let (tx, rx) = mpsc::channel(256);
-    let mut handles = vec![];
+    let (pipeline_tx, pipeline_rx) = mpsc::channel(256);
+    let (commands, _) = broadcast::channel(64);
+    let (states, _) = broadcast::channel(256);
+    let (status_tx, status_rx) = mpsc::channel(16);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let (pipeline_shutdown_tx, pipeline_shutdown_rx) = oneshot::channel();
 
     info!("starting \"cache_manager\"...");
-    handles.push(tokio::spawn(async move {
+    let commands2 = commands.clone();
+    let states2 = states.clone();
+    let mut cache_manager = tokio::spawn(async move {
         // start cache manager
         let cache_manager = CacheManager {
             enabled: !cli.nocache,
             endpoints: rszurro.endpoints,
+            commands: commands2,
+            states: states2,
+            path: rszurro.cache,
         };
 
-        cache_manager.run(rx).await;
-    }));
+        cache_manager.run(pipeline_rx, status_rx, shutdown_rx).await;
+    });
+
+    // start processing stages between watchers and cache manager
+    info!("starting \"pipeline\"...");
+    let pipeline = Pipeline::new(&rszurro.integration_path).await;
+    let pipeline = tokio::spawn(pipeline.run(rx, pipeline_tx, pipeline_shutdown_rx));
 
     // start configured watchers
     for watcher in rszurro.watchers {
@@ -55,8 +84,30 @@ async fn main() {
         );
         let tx2 = tx.clone();
 
-        handles.push(watcher.run(tx2).await);
+        watcher
+            .run(tx2, commands.clone(), states.clone(), status_tx.clone())
+            .await;
+    }
+
+    // run until terminated, then let endpoints say goodbye
+    tokio::select! {
+        _ = shutdown_signal() => {
+            info!("shutting down...");
+            let _ = shutdown_tx.send(());
+            let _ = pipeline_shutdown_tx.send(());
+            let _ = cache_manager.await;
+            let _ = pipeline.await;
+        }
+        _ = &mut cache_manager => {}
     }
+}
 
-    futures::future::join_all(handles).await;
+async fn shutdown_signal() {
+    // wait for ctrl-c or SIGTERM
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
 }
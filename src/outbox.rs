@@ -0,0 +1,146 @@
+use log::{debug, warn};
+use std::collections::VecDeque;
+use tokio::time::{Duration, Instant};
+
+use crate::{load_json, save_json, Client, Endpoint, SensorUpdate};
+
+// time allowed for the broker to acknowledge replayed updates
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Outbox {
+    capacity: usize,
+    policy: String,
+    path: String,
+    updates: VecDeque<SensorUpdate>,
+
+    // updates replayed but not acknowledged yet, with their client
+    in_flight: Option<(usize, Client, Instant)>,
+
+    // not saved to disk yet
+    changed: bool,
+}
+impl Outbox {
+    pub async fn new(endpoint: &Endpoint) -> Self {
+        // restore updates left over by a previous run
+        let what = format!("{} outbox", &endpoint.name);
+
+        Self {
+            capacity: endpoint.outbox,
+            policy: endpoint.outbox_policy.clone(),
+            path: endpoint.outbox_path.clone(),
+            updates: load_json(&endpoint.outbox_path, &what).await,
+            in_flight: None,
+            changed: false,
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    pub fn push(&mut self, endpoint: &Endpoint, update: SensorUpdate) {
+        // only the latest value of each sensor is worth replaying
+        if self.policy == "keep_latest" {
+            let mut index = 0;
+
+            while index < self.updates.len() {
+                let queued = &self.updates[index];
+
+                match queued.device_name == update.device_name
+                    && queued.sensor.name == update.sensor.name
+                {
+                    true => self.remove(index),
+                    false => index += 1,
+                }
+            }
+        }
+
+        // drop the oldest updates when full
+        while let Some(dropped) = self.updates.front() {
+            if self.updates.len() < self.capacity {
+                break;
+            }
+
+            warn!(
+                "{}: outbox full, {} {} dropped",
+                &endpoint.name, &dropped.device_name, &dropped.sensor.name
+            );
+            self.remove(0);
+        }
+
+        debug!(
+            "{}: {} {} queued, {} in outbox",
+            &endpoint.name,
+            &update.device_name,
+            &update.sensor.name,
+            self.updates.len() + 1
+        );
+        self.updates.push_back(update);
+        self.changed = true;
+    }
+
+    fn remove(&mut self, index: usize) {
+        // keep the count of replayed updates in step with the queue
+        if let Some((sent, _, _)) = &mut self.in_flight {
+            if index < *sent {
+                *sent -= 1;
+            }
+        }
+
+        self.updates.remove(index);
+    }
+
+    pub async fn flush(&mut self, endpoint: &Endpoint, client: &Client) {
+        // drop the previous replay once acknowledged, without waiting for it
+        if let Some((sent, sent_client, sent_at)) = &self.in_flight {
+            if sent_client.acknowledged() {
+                self.updates.drain(..*sent);
+                self.changed = true;
+
+                debug!(
+                    "{}: {} updates replayed, {} left in outbox",
+                    &endpoint.name,
+                    sent,
+                    self.updates.len()
+                );
+            } else if sent_at.elapsed() < ACK_TIMEOUT {
+                return;
+            } else {
+                warn!(
+                    "{}: {} updates not acknowledged, replaying them again",
+                    &endpoint.name, sent
+                );
+            }
+
+            self.in_flight = None;
+        }
+
+        // replay queued updates in order, stop at the first failure
+        let mut sent = 0;
+
+        for update in &self.updates {
+            match endpoint.run(update.clone(), client.clone()).await.await {
+                Ok(true) => sent += 1,
+                _ => break,
+            };
+        }
+
+        // keep updates until delivered, they are replayed again otherwise
+        if sent > 0 {
+            self.in_flight = Some((sent, client.clone(), Instant::now()));
+        }
+    }
+
+    pub async fn save(&mut self, endpoint: &Endpoint) {
+        // persist outbox to disk if configured and changed
+        if self.path.is_empty() || !self.changed {
+            return;
+        }
+
+        let what = format!("{} outbox", &endpoint.name);
+        self.changed = !save_json(&self.path, &self.updates, &what).await;
+    }
+}
@@ -0,0 +1,91 @@
+use log::debug;
+use serde_yaml::Value;
+use std::path::Path;
+
+// device profiles embedded in the binary
+const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    (
+        "hyd6000zsshp",
+        include_str!("../profiles/hyd6000zsshp.yaml"),
+    ),
+    ("sdm120", include_str!("../profiles/sdm120.yaml")),
+    ("sdm630", include_str!("../profiles/sdm630.yaml")),
+];
+
+pub fn resolve(config: &mut Value) -> Result<(), Box<dyn std::error::Error>> {
+    // expand slaves' profiles into their sensors list
+    let directory = config["profiles"].as_str().unwrap_or_default().to_string();
+
+    let Some(watchers) = config.get_mut("watchers").and_then(Value::as_sequence_mut) else {
+        return Ok(());
+    };
+
+    for watcher in watchers {
+        let Some(slaves) = watcher.get_mut("slaves").and_then(Value::as_sequence_mut) else {
+            continue;
+        };
+
+        for slave in slaves {
+            let Some(name) = slave.get("profile").and_then(Value::as_str) else {
+                continue;
+            };
+
+            // slave's sensors override profile's ones with the same name
+            let sensors = load(name, &directory)?;
+            let overrides = slave
+                .get("sensors")
+                .and_then(Value::as_sequence)
+                .cloned()
+                .unwrap_or_default();
+
+            slave["sensors"] = Value::Sequence(merge(sensors, overrides));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn load(name: &str, directory: &str) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    // look for a profile in profiles directory first, then in built-in ones
+    let path = ["yaml", "yml", "json"]
+        .iter()
+        .map(|extension| Path::new(directory).join(format!("{}.{}", name, extension)))
+        .find(|path| !directory.is_empty() && path.is_file());
+
+    let content = match path {
+        Some(path) => {
+            debug!("loading profile {} from {}", name, path.display());
+            std::fs::read_to_string(path)?
+        }
+        None => BUILTIN_PROFILES
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, content)| content.to_string())
+            .ok_or(format!("unknown device profile: {}", name))?,
+    };
+
+    // json is valid yaml too
+    let profile: Value = serde_yaml::from_str(&content)?;
+
+    Ok(profile
+        .get("sensors")
+        .and_then(Value::as_sequence)
+        .cloned()
+        .unwrap_or_default())
+}
+
+fn merge(mut sensors: Vec<Value>, overrides: Vec<Value>) -> Vec<Value> {
+    // merge overrides field by field, append unknown sensors
+    for sensor_override in overrides {
+        let sensor = sensors.iter_mut().find(|sensor| {
+            sensor.get("name").is_some() && sensor.get("name") == sensor_override.get("name")
+        });
+
+        match (sensor, sensor_override) {
+            (Some(Value::Mapping(sensor)), Value::Mapping(fields)) => sensor.extend(fields),
+            (_, sensor_override) => sensors.push(sensor_override),
+        }
+    }
+
+    sensors
+}
@@ -0,0 +1,364 @@
+use crate::{Sensor, SensorValue};
+
+#[derive(Debug)]
+pub enum Registers {
+    Words(Vec<u16>),
+    Bits(Vec<bool>),
+}
+impl Registers {
+    pub fn slice(&self, offset: usize, count: usize) -> Option<Registers> {
+        // get a sub range of registers
+        match self {
+            Registers::Words(words) => words
+                .get(offset..offset + count)
+                .map(|words| Registers::Words(words.to_vec())),
+            Registers::Bits(bits) => bits
+                .get(offset..offset + count)
+                .map(|bits| Registers::Bits(bits.to_vec())),
+        }
+    }
+}
+
+pub fn register_count(sensor: &Sensor) -> u16 {
+    // number of registers holding the sensor value
+    match sensor.count {
+        _ if is_bit(&sensor.register) => 1,
+        0 => match sensor.data_type.as_str() {
+            "u32" | "i32" | "f32" => 2,
+            "u64" | "f64" => 4,
+            _ => 1,
+        },
+        count => count,
+    }
+}
+
+pub fn register_kind(register: &str) -> &'static str {
+    // normalize register kind, holding registers by default
+    match register {
+        "input" => "input",
+        "coil" => "coil",
+        "discrete_input" => "discrete_input",
+        _ => "holding",
+    }
+}
+
+pub fn is_bit(register: &str) -> bool {
+    // coils and discrete inputs hold a single bit
+    matches!(register, "coil" | "discrete_input")
+}
+
+pub fn validate(sensor: &Sensor) -> Result<(), String> {
+    // check data type and register count once, instead of failing every poll
+    if is_bit(&sensor.register) {
+        return Ok(());
+    }
+
+    let (min, max) = match sensor.data_type.as_str() {
+        "" | "u16" | "i16" => (1, 1),
+        "u32" | "i32" | "f32" => (2, 2),
+        "u64" | "f64" => (4, 4),
+        "bitfield" => (1, 4),
+        "string" => (1, 125),
+        data_type => return Err(format!("unknown data type {}", data_type)),
+    };
+
+    match register_count(sensor) {
+        count if count < min || count > max => Err(format!(
+            "{} registers can't hold a {}",
+            count, &sensor.data_type
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn decode(sensor: &Sensor, registers: &Registers) -> Option<SensorValue> {
+    // decode registers into a sensor value
+    let words = match registers {
+        Registers::Bits(bits) => return bits.first().map(|&bit| SensorValue::IsBool(bit)),
+        Registers::Words(words) => words,
+    };
+    let count = register_count(sensor) as usize;
+
+    if words.len() < count {
+        return None;
+    }
+
+    let words = &words[..count];
+
+    // strings are sequential, only bytes inside each register may be swapped
+    let bytes = match sensor.data_type.as_str() {
+        "string" => to_bytes(words, &sensor.byte_order),
+        _ => to_bytes(&order_words(words, &sensor.word_order), &sensor.byte_order),
+    };
+
+    let raw = match sensor.data_type.as_str() {
+        "" | "u16" => u16::from_be_bytes(bytes.get(..2)?.try_into().ok()?) as f64,
+        "i16" => i16::from_be_bytes(bytes.get(..2)?.try_into().ok()?) as f64,
+        "u32" => u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as f64,
+        "i32" => i32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as f64,
+        "u64" => u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?) as f64,
+        "f32" => f32::from_be_bytes(bytes.get(..4)?.try_into().ok()?) as f64,
+        "f64" => f64::from_be_bytes(bytes.get(..8)?.try_into().ok()?),
+
+        "string" => {
+            let value = String::from_utf8_lossy(&bytes);
+            return Some(SensorValue::IsString(
+                value
+                    .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+                    .to_string(),
+            ));
+        }
+
+        "bitfield" => {
+            let bits = bytes.iter().map(|byte| format!("{:08b}", byte));
+            return Some(SensorValue::IsString(bits.collect()));
+        }
+
+        _ => return None,
+    };
+
+    // Scale value and truncate it at two digit
+    Some(SensorValue::IsF64(
+        f64::trunc((raw * sensor.accuracy + sensor.offset) * 100.0) / 100.0,
+    ))
+}
+
+pub fn encode(sensor: &Sensor, value: &SensorValue) -> Option<Vec<u16>> {
+    // encode a sensor value into registers, reverse of decode
+    let count = register_count(sensor) as usize;
+
+    let bytes = match (sensor.data_type.as_str(), value) {
+        ("string", SensorValue::IsString(value)) => {
+            let mut bytes = value.as_bytes().to_vec();
+            bytes.resize(count * 2, 0);
+            return Some(from_bytes(&bytes, &sensor.byte_order));
+        }
+
+        ("bitfield", SensorValue::IsString(value)) => u64::from_str_radix(value, 2)
+            .ok()
+            .filter(|bits| count >= 4 || *bits < 1 << (16 * count))?
+            .to_be_bytes()
+            .get(8usize.checked_sub(count * 2)?..)?
+            .to_vec(),
+
+        (data_type, value) => {
+            // Remove value scaling
+            let raw = match value {
+                SensorValue::IsF64(value) => (value - sensor.offset) / sensor.accuracy,
+                SensorValue::IsBool(value) => *value as u8 as f64,
+                _ => return None,
+            };
+
+            // refuse values the register can't hold instead of saturating
+            let (min, max) = match data_type {
+                "" | "u16" => (0.0, u16::MAX as f64),
+                "i16" => (i16::MIN as f64, i16::MAX as f64),
+                "u32" => (0.0, u32::MAX as f64),
+                "i32" => (i32::MIN as f64, i32::MAX as f64),
+                "u64" => (0.0, u64::MAX as f64),
+                "f32" => (f32::MIN as f64, f32::MAX as f64),
+                "f64" => (f64::MIN, f64::MAX),
+                "bitfield" => (0.0, 2f64.powi(16 * count as i32) - 1.0),
+                _ => return None,
+            };
+
+            let rounded = match data_type {
+                "f32" | "f64" => raw,
+                _ => raw.round(),
+            };
+
+            if !(min..=max).contains(&rounded) {
+                return None;
+            }
+
+            match data_type {
+                "" | "u16" => (rounded as u16).to_be_bytes().to_vec(),
+                "i16" => (rounded as i16).to_be_bytes().to_vec(),
+                "u32" => (rounded as u32).to_be_bytes().to_vec(),
+                "i32" => (rounded as i32).to_be_bytes().to_vec(),
+                "u64" => (rounded as u64).to_be_bytes().to_vec(),
+                "f32" => (rounded as f32).to_be_bytes().to_vec(),
+                "f64" => rounded.to_be_bytes().to_vec(),
+                _ => (rounded as u64)
+                    .to_be_bytes()
+                    .get(8usize.checked_sub(count * 2)?..)?
+                    .to_vec(),
+            }
+        }
+    };
+
+    Some(order_words(
+        &from_bytes(&bytes, &sensor.byte_order),
+        &sensor.word_order,
+    ))
+}
+
+fn order_words(words: &[u16], word_order: &str) -> Vec<u16> {
+    // most significant register first unless word order is little endian
+    match word_order {
+        "little" => words.iter().rev().copied().collect(),
+        _ => words.to_vec(),
+    }
+}
+
+fn to_bytes(words: &[u16], byte_order: &str) -> Vec<u8> {
+    // most significant byte first unless byte order is little endian
+    words
+        .iter()
+        .flat_map(|word| match byte_order {
+            "little" => word.to_le_bytes(),
+            _ => word.to_be_bytes(),
+        })
+        .collect()
+}
+
+fn from_bytes(bytes: &[u8], byte_order: &str) -> Vec<u16> {
+    // most significant byte first unless byte order is little endian
+    bytes
+        .chunks_exact(2)
+        .map(|pair| match byte_order {
+            "little" => u16::from_le_bytes([pair[0], pair[1]]),
+            _ => u16::from_be_bytes([pair[0], pair[1]]),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(data_type: &str, word_order: &str, byte_order: &str) -> Sensor {
+        Sensor {
+            name: "test".to_string(),
+            data_type: data_type.to_string(),
+            word_order: word_order.to_string(),
+            byte_order: byte_order.to_string(),
+            accuracy: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip_across_orders() {
+        let values = [
+            ("u16", 65535.0),
+            ("i16", -1234.0),
+            ("u32", 305419896.0),
+            ("i32", -305419896.0),
+            ("u64", 1234567890123.0),
+            ("f32", -1234.5),
+            ("f64", 98765.25),
+        ];
+
+        for (data_type, value) in values {
+            for word_order in ["big", "little"] {
+                for byte_order in ["big", "little"] {
+                    let sensor = sensor(data_type, word_order, byte_order);
+                    let words = encode(&sensor, &SensorValue::IsF64(value)).unwrap();
+
+                    assert_eq!(words.len(), register_count(&sensor) as usize);
+                    assert_eq!(
+                        decode(&sensor, &Registers::Words(words)),
+                        Some(SensorValue::IsF64(value)),
+                        "{} {} words {} bytes",
+                        data_type,
+                        word_order,
+                        byte_order
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn word_and_byte_order() {
+        let value = SensorValue::IsF64(0x12345678 as f64);
+
+        for (word_order, byte_order, expected) in [
+            ("big", "big", [0x1234, 0x5678]),
+            ("little", "big", [0x5678, 0x1234]),
+            ("big", "little", [0x3412, 0x7856]),
+            ("little", "little", [0x7856, 0x3412]),
+        ] {
+            let sensor = sensor("u32", word_order, byte_order);
+            assert_eq!(encode(&sensor, &value).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn scaling_and_strings() {
+        let scaled = Sensor {
+            accuracy: 0.1,
+            offset: -40.0,
+            ..sensor("i16", "", "")
+        };
+        let words = encode(&scaled, &SensorValue::IsF64(21.5)).unwrap();
+        assert_eq!(words, [615]);
+        assert_eq!(
+            decode(&scaled, &Registers::Words(words)),
+            Some(SensorValue::IsF64(21.5))
+        );
+
+        let text = Sensor {
+            count: 3,
+            ..sensor("string", "", "")
+        };
+        let words = encode(&text, &SensorValue::IsString("SDM630".to_string())).unwrap();
+        assert_eq!(
+            decode(&text, &Registers::Words(words)),
+            Some(SensorValue::IsString("SDM630".to_string()))
+        );
+
+        let bits = sensor("bitfield", "", "");
+        let words = encode(
+            &bits,
+            &SensorValue::IsString("1000000000000101".to_string()),
+        );
+        assert_eq!(words, Some(vec![0x8005]));
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected() {
+        for (data_type, value) in [
+            ("u16", 65536.0),
+            ("u16", -1.0),
+            ("i16", 32768.0),
+            ("i16", -32769.0),
+            ("u32", 4294967296.0),
+            ("i32", -2147483649.0),
+            ("u64", -1.0),
+            ("f32", f64::MAX),
+            ("u16", f64::NAN),
+            ("f64", f64::NAN),
+        ] {
+            let sensor = sensor(data_type, "", "");
+            assert_eq!(
+                encode(&sensor, &SensorValue::IsF64(value)),
+                None,
+                "{} {}",
+                data_type,
+                value
+            );
+        }
+
+        let bits = sensor("bitfield", "", "");
+        let words = encode(&bits, &SensorValue::IsString("1".repeat(17)));
+        assert_eq!(words, None);
+    }
+
+    #[test]
+    fn validate_data_types_and_counts() {
+        assert!(validate(&sensor("u32", "", "")).is_ok());
+        assert!(validate(&sensor("float", "", "")).is_err());
+        assert!(validate(&Sensor {
+            count: 1,
+            ..sensor("f32", "", "")
+        })
+        .is_err());
+        assert!(validate(&Sensor {
+            count: 5,
+            ..sensor("bitfield", "", "")
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use tokio::time::{timeout, Duration};
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::{Client, SlaveContext};
+use tokio_serial::SerialPortBuilder;
+
+use crate::registers::Registers;
+use crate::watchers::{modbus, modbus_rtu};
+use crate::Watcher;
+
+#[derive(clap::Args)]
+pub struct ScanArgs {
+    /// Serial port device path
+    #[arg(value_name = "PATH")]
+    pub path: String,
+
+    /// Baud rates to probe
+    #[arg(long, value_delimiter = ',', default_values_t = [9600, 19200, 38400, 57600, 115200])]
+    pub baud_rates: Vec<u32>,
+
+    /// Slave ids to probe, e.g. 1-247
+    #[arg(long, default_value = "1-247", value_parser = parse_range)]
+    pub slaves: RangeInclusive<u16>,
+
+    /// Register addresses to read, e.g. 0-199
+    #[arg(long, default_value = "0-199", value_parser = parse_range)]
+    pub registers: RangeInclusive<u16>,
+
+    /// Register kinds to read, holding and/or input
+    #[arg(long, value_delimiter = ',', value_parser = ["holding", "input"], default_values_t = ["holding".to_string(), "input".to_string()])]
+    pub kinds: Vec<String>,
+
+    /// Registers per request
+    #[arg(long, default_value_t = 16)]
+    pub block: u16,
+
+    /// Response timeout in milliseconds
+    #[arg(long, default_value_t = 200)]
+    pub timeout: u64,
+
+    /// Serial parity: none, odd, even
+    #[arg(long, default_value = "none")]
+    pub parity: String,
+
+    /// Serial stop bits
+    #[arg(long, default_value_t = 1)]
+    pub stop_bits: u8,
+
+    /// Serial data bits
+    #[arg(long, default_value_t = 8)]
+    pub data_bits: u8,
+
+    /// Output format: table, yaml
+    #[arg(long, default_value = "table")]
+    pub format: String,
+}
+
+pub async fn run(args: ScanArgs) {
+    for &baud_rate in &args.baud_rates {
+        // reuse modbus_rtu serial settings
+        let watcher = Watcher {
+            path: args.path.clone(),
+            baud_rate,
+            parity: args.parity.clone(),
+            stop_bits: args.stop_bits,
+            data_bits: args.data_bits,
+            ..Default::default()
+        };
+        let builder = modbus_rtu::serial_builder(&watcher);
+
+        let mut ctx = match modbus_rtu::connect(&builder) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                eprintln!("unable to open {}: {}", &args.path, e);
+                return;
+            }
+        };
+
+        eprintln!("scanning {} at {} baud...", &args.path, baud_rate);
+
+        for slave in args.slaves.clone() {
+            let Ok(slave) = u8::try_from(slave) else {
+                break;
+            };
+            ctx.set_slave(tokio_modbus::slave::Slave(slave));
+
+            // any answer, even an exception, means the slave exists
+            let kind = args.kinds.first().map(String::as_str).unwrap_or("holding");
+            if read(
+                &mut ctx,
+                &builder,
+                &args,
+                slave,
+                kind,
+                *args.registers.start(),
+                1,
+            )
+            .await
+            .is_none()
+            {
+                continue;
+            }
+
+            eprintln!("found slave {} at {} baud", slave, baud_rate);
+            let registers = scan_slave(&mut ctx, &builder, &args, slave).await;
+
+            match args.format.as_str() {
+                "yaml" => print_yaml(baud_rate, slave, &registers),
+                _ => print_table(baud_rate, slave, &registers),
+            }
+        }
+
+        let _cls = ctx.disconnect().await;
+    }
+}
+
+async fn scan_slave(
+    ctx: &mut Context,
+    builder: &SerialPortBuilder,
+    args: &ScanArgs,
+    slave: u8,
+) -> BTreeMap<(String, u16), u16> {
+    // read register ranges block by block
+    let mut found = BTreeMap::new();
+
+    for kind in &args.kinds {
+        let mut address = *args.registers.start() as u32;
+
+        while address <= *args.registers.end() as u32 {
+            let count = (*args.registers.end() as u32 - address + 1).min(args.block.max(1) as u32);
+
+            match read(
+                ctx,
+                builder,
+                args,
+                slave,
+                kind,
+                address as u16,
+                count as u16,
+            )
+            .await
+            {
+                // whole block is readable
+                Some(Ok(words)) => {
+                    for (offset, word) in words.into_iter().enumerate() {
+                        found.insert((kind.clone(), address as u16 + offset as u16), word);
+                    }
+                }
+
+                // some registers in the block are invalid, read them one by one
+                Some(Err(_)) if count > 1 => {
+                    for single in address..address + count {
+                        if let Some(Ok(words)) =
+                            read(ctx, builder, args, slave, kind, single as u16, 1).await
+                        {
+                            found.insert((kind.clone(), single as u16), words[0]);
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+
+            address += count;
+        }
+    }
+
+    found
+}
+
+async fn read(
+    ctx: &mut Context,
+    builder: &SerialPortBuilder,
+    args: &ScanArgs,
+    slave: u8,
+    kind: &str,
+    address: u16,
+    count: u16,
+) -> Option<Result<Vec<u16>, tokio_modbus::ExceptionCode>> {
+    // read registers, None when the slave doesn't answer
+    match timeout(
+        Duration::from_millis(args.timeout),
+        modbus::read(ctx, kind, address, count),
+    )
+    .await
+    {
+        Ok(Ok(Ok(Registers::Words(words)))) => Some(Ok(words)),
+        Ok(Ok(Ok(Registers::Bits(_)))) => None,
+        Ok(Ok(Err(e))) => Some(Err(e)),
+
+        // garbage on the line, release the serial port before reopening it
+        Ok(Err(_)) | Err(_) => {
+            let _cls = ctx.disconnect().await;
+
+            match modbus_rtu::connect(builder) {
+                Ok(new_ctx) => {
+                    *ctx = new_ctx;
+                    ctx.set_slave(tokio_modbus::slave::Slave(slave));
+                }
+                Err(e) => eprintln!("unable to reopen {}: {}", &args.path, e),
+            }
+            None
+        }
+    }
+}
+
+fn print_table(baud_rate: u32, slave: u8, registers: &BTreeMap<(String, u16), u16>) {
+    println!("slave {} at {} baud", slave, baud_rate);
+    println!(
+        "{:<10} {:>7} {:>8} {:>7} {:>7}",
+        "register", "address", "hex", "u16", "i16"
+    );
+
+    for ((kind, address), word) in registers {
+        println!(
+            "{:<10} {:>7} {:>8} {:>7} {:>7}",
+            kind,
+            address,
+            format!("{:#06x}", word),
+            word,
+            *word as i16
+        );
+    }
+    println!();
+}
+
+fn print_yaml(baud_rate: u32, slave: u8, registers: &BTreeMap<(String, u16), u16>) {
+    println!("# slave {} at {} baud", slave, baud_rate);
+    println!("- address: {}", slave);
+    println!("  sensors:");
+
+    for (kind, address) in registers.keys() {
+        println!("  - name: {}_{}", kind, address);
+        println!("    register: {}", kind);
+        println!("    address: {}", address);
+    }
+    println!();
+}
+
+fn parse_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    // parse "first-last" or a single value
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first = first.trim().parse::<u16>().map_err(|e| e.to_string())?;
+    let last = last.trim().parse::<u16>().map_err(|e| e.to_string())?;
+
+    match first <= last {
+        true => Ok(first..=last),
+        false => Err(format!("invalid range {}", range)),
+    }
+}
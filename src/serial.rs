@@ -0,0 +1,33 @@
+use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilder, StopBits};
+
+pub fn builder(
+    path: &str,
+    baud_rate: u32,
+    parity: &str,
+    stop_bits: u8,
+    data_bits: u8,
+    flow_control: &str,
+) -> SerialPortBuilder {
+    // serial port settings, 8N1 without flow control by default
+    tokio_serial::new(path, baud_rate)
+        .parity(match parity {
+            "odd" => Parity::Odd,
+            "even" => Parity::Even,
+            _ => Parity::None,
+        })
+        .stop_bits(match stop_bits {
+            2 => StopBits::Two,
+            _ => StopBits::One,
+        })
+        .data_bits(match data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            _ => DataBits::Eight,
+        })
+        .flow_control(match flow_control {
+            "software" => FlowControl::Software,
+            "hardware" => FlowControl::Hardware,
+            _ => FlowControl::None,
+        })
+}
@@ -0,0 +1,138 @@
+use log::{debug, error};
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use super::round;
+use crate::{SensorUpdate, SensorValue};
+
+struct Window {
+    started: Instant,
+
+    // latest update received, used as a template for the aggregated one
+    update: SensorUpdate,
+    samples: Vec<f64>,
+}
+
+#[derive(Default)]
+pub struct Aggregate {
+    windows: HashMap<String, Window>,
+}
+impl Aggregate {
+    pub fn process(&mut self, update: SensorUpdate) -> Vec<SensorUpdate> {
+        // pass through sensors not aggregated and non numeric values
+        let SensorValue::IsF64(value) = update.value else {
+            return vec![update];
+        };
+
+        if update.sensor.aggregate.is_empty() {
+            return vec![update];
+        }
+
+        // nothing would ever close the window
+        if update.sensor.aggregate_window == 0 && update.sensor.aggregate_count == 0 {
+            error!(
+                "{} {}: aggregate needs aggregate_window or aggregate_count",
+                &update.device_name, &update.sensor.name
+            );
+            return vec![update];
+        }
+
+        let key = format!("{}/{}", &update.device_name, &update.sensor.name);
+        let window = self.windows.entry(key.clone()).or_insert_with(|| Window {
+            started: Instant::now(),
+            update: update.clone(),
+            samples: Vec::new(),
+        });
+
+        window.samples.push(value);
+        window.update = update;
+
+        // count windows close as soon as they are full
+        let count = window.update.sensor.aggregate_count;
+
+        match count > 0 && window.samples.len() >= count {
+            true => self.close(&key).into_iter().collect(),
+            false => Vec::new(),
+        }
+    }
+
+    pub fn expire(&mut self) -> Vec<SensorUpdate> {
+        // close every time window past its boundary
+        let now = Instant::now();
+
+        let expired: Vec<String> = self
+            .windows
+            .iter()
+            .filter(|(_, window)| window.deadline().is_some_and(|deadline| deadline <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        expired.iter().filter_map(|key| self.close(key)).collect()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.windows.values().filter_map(Window::deadline).min()
+    }
+
+    fn close(&mut self, key: &str) -> Option<SensorUpdate> {
+        // emit the aggregated value and start a new window
+        let window = self.windows.remove(key)?;
+        let sensor = &window.update.sensor;
+
+        let Some(value) = aggregate(&sensor.aggregate, window.samples.clone()) else {
+            error!(
+                "{} {}: unknown aggregate {}",
+                &window.update.device_name, &sensor.name, &sensor.aggregate
+            );
+            return None;
+        };
+
+        // round to the configured number of digits
+        let value = round(value, sensor.aggregate_precision);
+
+        debug!(
+            "{} {}: {} of {} samples => {}",
+            &window.update.device_name,
+            &sensor.name,
+            &sensor.aggregate,
+            window.samples.len(),
+            value
+        );
+
+        Some(SensorUpdate {
+            value: SensorValue::IsF64(value),
+            ..window.update
+        })
+    }
+}
+
+impl Window {
+    fn deadline(&self) -> Option<Instant> {
+        match self.update.sensor.aggregate_window {
+            0 => None,
+            window => Some(self.started + Duration::from_millis(window)),
+        }
+    }
+}
+
+fn aggregate(function: &str, mut samples: Vec<f64>) -> Option<f64> {
+    let count = samples.len() as f64;
+
+    match function {
+        "mean" => Some(samples.iter().sum::<f64>() / count),
+        "min" => samples.into_iter().reduce(f64::min),
+        "max" => samples.into_iter().reduce(f64::max),
+        "sum" => Some(samples.iter().sum()),
+        "last" => samples.pop(),
+        "median" => {
+            samples.sort_by(f64::total_cmp);
+
+            let middle = samples.len() / 2;
+            match samples.len() % 2 {
+                0 => Some((samples[middle - 1] + samples[middle]) / 2.0),
+                _ => Some(samples[middle]),
+            }
+        }
+        _ => None,
+    }
+}
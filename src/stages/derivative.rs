@@ -0,0 +1,101 @@
+use log::error;
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+
+use super::{derived_sensor, round, unit_time};
+use crate::{Sensor, SensorUpdate, SensorValue};
+
+#[derive(Default)]
+struct Slope {
+    samples: VecDeque<(Instant, f64)>,
+
+    // smoothed rate, for ema
+    rate: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct Derivative {
+    slopes: HashMap<String, Slope>,
+}
+impl Derivative {
+    pub fn process(&mut self, update: SensorUpdate) -> Vec<SensorUpdate> {
+        // pass through sensors not derived and non numeric values
+        let SensorValue::IsF64(value) = update.value else {
+            return vec![update];
+        };
+
+        let sensor = &update.sensor;
+
+        if sensor.derivative.is_empty() {
+            return vec![update];
+        }
+
+        if !["simple", "window", "ema"].contains(&sensor.derivative.as_str()) {
+            error!(
+                "{} {}: unknown derivative {}",
+                &update.device_name, &sensor.name, &sensor.derivative
+            );
+            return vec![update];
+        }
+
+        let Some(hours) = unit_time(&sensor.derivative_time) else {
+            error!(
+                "{} {}: invalid derivative unit {}",
+                &update.device_name, &sensor.name, &sensor.derivative_time
+            );
+            return vec![update];
+        };
+
+        let key = format!("{}/{}", &update.device_name, &sensor.name);
+        let slope = self.slopes.entry(key).or_default();
+        let window = Duration::from_millis(sensor.derivative_window);
+        let now = Instant::now();
+
+        slope.samples.push_back((now, value));
+
+        // keep the oldest sample within the window, or just the previous one
+        while slope.samples.len() > 2
+            && (sensor.derivative != "window" || now.duration_since(slope.samples[1].0) >= window)
+        {
+            slope.samples.pop_front();
+        }
+
+        let (first_time, first_value) = slope.samples[0];
+        let elapsed = now.duration_since(first_time).as_secs_f64() / 3600.0 / hours;
+
+        if elapsed == 0.0 {
+            return vec![update];
+        }
+
+        let rate = (value - first_value) / elapsed;
+
+        let rate = match (sensor.derivative.as_str(), slope.rate) {
+            // exponential moving average with derivative_window as time constant
+            ("ema", Some(previous)) if !window.is_zero() => {
+                let alpha = 1.0
+                    - (-now.duration_since(first_time).as_secs_f64() / window.as_secs_f64()).exp();
+                previous + alpha * (rate - previous)
+            }
+            _ => rate,
+        };
+
+        slope.rate = Some(rate);
+
+        // round to the configured number of digits
+        let rate = round(rate, sensor.derivative_precision);
+
+        let derivative = SensorUpdate {
+            platform: update.platform.clone(),
+            device_name: update.device_name.clone(),
+            sensor: Sensor {
+                unit: format!("{}/{}", &sensor.unit, &sensor.derivative_time),
+                state_class: "measurement".to_string(),
+                ..derived_sensor(sensor, "rate")
+            },
+            value: SensorValue::IsF64(rate),
+            last_value: SensorValue::None,
+        };
+
+        vec![update, derivative]
+    }
+}
@@ -0,0 +1,224 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, Timelike};
+use log::{debug, error};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+use super::{derived_sensor, round, unit_time};
+use crate::{load_json, save_json, Sensor, SensorUpdate, SensorValue};
+
+#[derive(Deserialize, Serialize, Default)]
+struct Accumulator {
+    total: f64,
+
+    // reset cycle the total belongs to, e.g. 2025-03 for monthly
+    #[serde(default)]
+    period: String,
+
+    // previous sample, not persisted as restarts leave a gap
+    #[serde(skip)]
+    last: Option<(Instant, DateTime<Local>, f64)>,
+}
+
+pub struct Integration {
+    path: String,
+    accumulators: HashMap<String, Accumulator>,
+    changed: bool,
+}
+impl Integration {
+    pub async fn new(path: &str) -> Self {
+        // restore totals from disk, if any
+        Self {
+            path: path.to_string(),
+            accumulators: load_json(path, "integration totals").await,
+            changed: false,
+        }
+    }
+
+    pub fn process(&mut self, update: SensorUpdate) -> Vec<SensorUpdate> {
+        // pass through sensors not integrated and non numeric values
+        let SensorValue::IsF64(value) = update.value else {
+            return vec![update];
+        };
+
+        let sensor = &update.sensor;
+
+        if sensor.integration.is_empty() {
+            return vec![update];
+        }
+
+        let (Some(prefix), Some(hours)) = (
+            unit_prefix(&sensor.integration_prefix),
+            unit_time(&sensor.integration_time),
+        ) else {
+            error!(
+                "{} {}: invalid integration unit {}{}",
+                &update.device_name,
+                &sensor.name,
+                &sensor.integration_prefix,
+                &sensor.integration_time
+            );
+            return vec![update];
+        };
+
+        let key = format!("{}/{}", &update.device_name, &sensor.name);
+        let accumulator = self.accumulators.entry(key).or_default();
+        let (now, wall_now) = (Instant::now(), Local::now());
+        let period = reset_period(&sensor.integration_reset, wall_now);
+        let mut integrals = Vec::new();
+
+        // area between two fractions of the interval since the previous sample
+        let area = |last_value: f64, elapsed: f64, from: f64, to: f64| {
+            let at = |x: f64| {
+                signed(
+                    &sensor.integration_sign,
+                    last_value + (value - last_value) * x,
+                )
+            };
+
+            let height = match sensor.integration.as_str() {
+                "left" => at(0.0),
+                "right" => at(1.0),
+                _ => (at(from) + at(to)) / 2.0,
+            };
+
+            height * (to - from) * elapsed / hours / prefix
+        };
+
+        // book the area up to the boundary to the ending cycle, then start over
+        let mut split = 0.0;
+
+        if accumulator.period != period {
+            if let Some((last_time, last_wall, last_value)) = accumulator.last {
+                let boundary = period_start(&sensor.integration_reset, wall_now);
+                let span = (wall_now - last_wall).num_milliseconds() as f64;
+
+                split = match (boundary, span > 0.0) {
+                    (Some(boundary), true) => {
+                        ((boundary - last_wall).num_milliseconds() as f64 / span).clamp(0.0, 1.0)
+                    }
+                    _ => 0.0,
+                };
+
+                let elapsed = now.duration_since(last_time).as_secs_f64() / 3600.0;
+                accumulator.total += area(last_value, elapsed, 0.0, split);
+
+                debug!(
+                    "{} {}: {} integration reset",
+                    &update.device_name, &sensor.name, &sensor.integration_reset
+                );
+                integrals.push(accumulator.total);
+            }
+
+            accumulator.total = 0.0;
+            accumulator.period = period;
+        }
+
+        // area since the previous sample, or the boundary
+        if let Some((last_time, _, last_value)) = accumulator.last {
+            let elapsed = now.duration_since(last_time).as_secs_f64() / 3600.0;
+            accumulator.total += area(last_value, elapsed, split, 1.0);
+        }
+
+        accumulator.last = Some((now, wall_now, value));
+        self.changed = true;
+        integrals.push(accumulator.total);
+
+        // totals only grow when integrating one sign
+        let state_class = match sensor.integration_sign.as_str() {
+            "positive" | "negative" => "total_increasing",
+            _ => "total",
+        };
+
+        let integral = Sensor {
+            unit: format!(
+                "{}{}{}",
+                &sensor.integration_prefix, &sensor.unit, &sensor.integration_time
+            ),
+            state_class: state_class.to_string(),
+            device_class: match sensor.device_class.as_str() {
+                "power" => "energy".to_string(),
+                _ => "".to_string(),
+            },
+            ..derived_sensor(sensor, "energy")
+        };
+
+        let mut updates = vec![update.clone()];
+
+        // round to the configured number of digits
+        for total in integrals {
+            updates.push(SensorUpdate {
+                platform: update.platform.clone(),
+                device_name: update.device_name.clone(),
+                sensor: integral.clone(),
+                value: SensorValue::IsF64(round(total, sensor.integration_precision)),
+                last_value: SensorValue::None,
+            });
+        }
+
+        updates
+    }
+
+    pub async fn save(&mut self) {
+        // write totals atomically when they changed
+        if !self.changed || self.path.is_empty() {
+            return;
+        }
+
+        self.changed = !save_json(&self.path, &self.accumulators, "integration totals").await;
+    }
+}
+
+fn unit_prefix(prefix: &str) -> Option<f64> {
+    match prefix {
+        "" => Some(1.0),
+        "k" => Some(1e3),
+        "M" => Some(1e6),
+        "G" => Some(1e9),
+        _ => None,
+    }
+}
+
+fn signed(sign: &str, value: f64) -> f64 {
+    // integrate one side only, e.g. grid import or export
+    match sign {
+        "positive" => value.max(0.0),
+        "negative" => (-value).max(0.0),
+        _ => value,
+    }
+}
+
+fn reset_period(cycle: &str, now: DateTime<Local>) -> String {
+    // local calendar period, totals never reset when empty
+    let format = match cycle {
+        "hourly" => "%Y-%m-%d %H",
+        "daily" => "%Y-%m-%d",
+        "weekly" => "%G-W%V",
+        "monthly" => "%Y-%m",
+        "yearly" => "%Y",
+        _ => return String::new(),
+    };
+
+    now.format(format).to_string()
+}
+
+fn period_start(cycle: &str, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    // local time the current period began
+    let today = now.date_naive();
+
+    let (day, hour) = match cycle {
+        "hourly" => (today, now.hour()),
+        "daily" => (today, 0),
+        "weekly" => (
+            today - Duration::days(now.weekday().num_days_from_monday() as i64),
+            0,
+        ),
+        "monthly" => (NaiveDate::from_ymd_opt(now.year(), now.month(), 1)?, 0),
+        "yearly" => (NaiveDate::from_ymd_opt(now.year(), 1, 1)?, 0),
+        _ => return None,
+    };
+
+    day.and_hms_opt(hour, 0, 0)?
+        .and_local_timezone(Local)
+        .earliest()
+}
@@ -0,0 +1,111 @@
+pub mod aggregate;
+pub mod derivative;
+pub mod integration;
+
+use log::debug;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, sleep_until, Duration, Instant};
+
+use crate::{Sensor, SensorUpdate};
+
+// how often integration totals are written when changed
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Pipeline {
+    integration: integration::Integration,
+    derivative: derivative::Derivative,
+    aggregate: aggregate::Aggregate,
+}
+impl Pipeline {
+    pub async fn new(path: &str) -> Self {
+        // restore stages' state
+        Self {
+            integration: integration::Integration::new(path).await,
+            derivative: derivative::Derivative::default(),
+            aggregate: aggregate::Aggregate::default(),
+        }
+    }
+
+    pub async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<SensorUpdate>,
+        tx: mpsc::Sender<SensorUpdate>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) {
+        let mut save_interval = interval(SAVE_INTERVAL);
+
+        // process watcher updates before the cache manager
+        loop {
+            // wake up when the first window closes
+            let next_window = self.aggregate.next_deadline();
+
+            let updates = tokio::select! {
+                update = rx.recv() => match update {
+                    Some(update) => self.process(update),
+                    None => break,
+                },
+                _ = sleep_until(next_window.unwrap_or_else(Instant::now)), if next_window.is_some() => {
+                    self.aggregate.expire()
+                }
+                _ = save_interval.tick() => {
+                    self.integration.save().await;
+                    continue;
+                }
+                _ = &mut shutdown => break,
+            };
+
+            for update in updates {
+                if tx.send(update).await.is_err() {
+                    debug!("cache manager gone, stopping pipeline");
+                    return;
+                }
+            }
+        }
+
+        self.integration.save().await;
+    }
+
+    fn process(&mut self, update: SensorUpdate) -> Vec<SensorUpdate> {
+        // run an update through every stage
+        self.integration
+            .process(update)
+            .into_iter()
+            .flat_map(|update| self.derivative.process(update))
+            .flat_map(|update| self.aggregate.process(update))
+            .collect()
+    }
+}
+
+pub fn round(value: f64, digits: u8) -> f64 {
+    // round to a number of decimal digits
+    let scale = 10f64.powi(digits as i32);
+    (value * scale).round() / scale
+}
+
+fn unit_time(time: &str) -> Option<f64> {
+    // in hours
+    match time {
+        "s" => Some(1.0 / 3600.0),
+        "min" => Some(1.0 / 60.0),
+        "h" => Some(1.0),
+        "d" => Some(24.0),
+        _ => None,
+    }
+}
+
+fn derived_sensor(source: &Sensor, suffix: &str) -> Sensor {
+    // sensor computed from another one, sharing its dispatch settings
+    Sensor {
+        name: format!("{}_{}", &source.name, suffix),
+        friendly_name: match source.friendly_name.is_empty() {
+            true => format!("{} {}", &source.name, suffix),
+            false => format!("{} {}", &source.friendly_name, suffix),
+        },
+        deadband: source.deadband,
+        deadband_percent: source.deadband_percent,
+        hysteresis: source.hysteresis,
+        min_interval: source.min_interval,
+        max_interval: source.max_interval,
+        ..Default::default()
+    }
+}
@@ -12,6 +12,7 @@ pub async fn run(
 
     for sensor in watcher.sensors.clone() {
         let chip_name = watcher.chip.clone();
+        let platform = watcher.platform.clone();
         let sensor_address = u32::from(sensor.address);
         let tx2 = tx.clone();
 
@@ -54,7 +55,14 @@ pub async fn run(
                 trace!("{} {} event: {:?}", &chip_name, &sensor.address, event);
 
                 // Send value to endpoints only if the state is stable
-                update_sensor(&tx2, &chip_name, &sensor, SensorValue::IsBool(sensor_value)).await;
+                update_sensor(
+                    &tx2,
+                    &platform,
+                    &chip_name,
+                    &sensor,
+                    SensorValue::IsBool(sensor_value),
+                )
+                .await;
             }
         }));
     }
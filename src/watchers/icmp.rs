@@ -33,7 +33,14 @@ pub async fn run(
         let sensor = Sensor::new("status".to_string(), watcher.name.clone()).await;
 
         // update sensor cache
-        update_sensor(&tx, &watcher.name, &sensor, SensorValue::IsBool(value)).await;
+        update_sensor(
+            &tx,
+            &watcher.platform,
+            &watcher.name,
+            &sensor,
+            SensorValue::IsBool(value),
+        )
+        .await;
 
         // sleep for next update
         sleep(Duration::from_millis(watcher.scan_interval)).await;
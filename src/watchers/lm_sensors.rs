@@ -60,11 +60,13 @@ pub fn run(
                             debounce_delay: 0,
                             state_class: "measurement".to_string(),
                             device_class: device_class.clone(),
+                            ..Default::default()
                         };
 
                         // Send value to Home Assistant
                         update_sensor_sync(
                             &tx,
+                            &watcher.platform,
                             &watcher.name,
                             &sensor,
                             SensorValue::IsF64(float_value),
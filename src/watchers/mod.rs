@@ -1,9 +1,15 @@
 #[cfg(feature = "lmsensors")]
 pub mod lm_sensors;
 
+#[cfg(any(feature = "modbus-rtu", feature = "modbus-tcp"))]
+pub mod modbus;
+
 #[cfg(feature = "modbus-rtu")]
 pub mod modbus_rtu;
 
+#[cfg(feature = "modbus-tcp")]
+pub mod modbus_tcp;
+
 #[cfg(feature = "sysinfo")]
 pub mod sysinfo;
 
@@ -12,3 +18,9 @@ pub mod gpio;
 
 #[cfg(feature = "icmp")]
 pub mod icmp;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+
+#[cfg(feature = "template")]
+pub mod template;
@@ -0,0 +1,527 @@
+use log::{error, info, trace, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::{Client, Reader, SlaveContext, Writer};
+
+use crate::registers::{
+    decode, encode, is_bit, register_count, register_kind, validate, Registers,
+};
+use crate::{update_sensor, Sensor, SensorCommand, SensorUpdate, SensorValue, Slave, Watcher};
+
+// prevent some hangs while talking to modbus devices
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+// delays between reconnection attempts
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// write commands older than this are dropped, e.g. after the bus was down
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// maximum registers and bits per request allowed by the protocol
+const MAX_REGISTERS: u32 = 125;
+const MAX_BITS: u32 = 2000;
+
+// buses shared between watchers, keyed by serial path or gateway address
+static BUSES: OnceLock<std::sync::Mutex<HashMap<String, Arc<Mutex<Bus>>>>> = OnceLock::new();
+
+pub enum Transport {
+    #[cfg(feature = "modbus-rtu")]
+    Rtu(tokio_serial::SerialPortBuilder),
+
+    #[cfg(feature = "modbus-tcp")]
+    Tcp(String, u16),
+}
+impl Transport {
+    async fn connect(&self) -> std::io::Result<Context> {
+        // open a new modbus connection
+        match self {
+            #[cfg(feature = "modbus-rtu")]
+            Transport::Rtu(builder) => crate::watchers::modbus_rtu::connect(builder),
+
+            #[cfg(feature = "modbus-tcp")]
+            Transport::Tcp(host, port) => crate::watchers::modbus_tcp::connect(host, *port).await,
+        }
+    }
+}
+
+pub struct Bus {
+    pub key: String,
+    transport: Transport,
+    settings: String,
+    ctx: Option<Context>,
+    frame_delay: u64,
+    frame_gap: Duration,
+    last_frame: Instant,
+    backoff: Duration,
+    retry_at: Instant,
+}
+impl Bus {
+    pub async fn context(&mut self, slave: u8) -> Option<&mut Context> {
+        // wait for the inter-frame gap since the last request
+        sleep_until(self.last_frame + self.frame_gap).await;
+
+        let ctx = match self.ctx.take() {
+            Some(ctx) => ctx,
+            None => self.connect().await?,
+        };
+
+        let ctx = self.ctx.insert(ctx);
+        ctx.set_slave(tokio_modbus::slave::Slave(slave));
+        Some(ctx)
+    }
+
+    pub fn done(&mut self) {
+        // mark the end of a request
+        self.last_frame = Instant::now();
+    }
+
+    pub async fn reset(&mut self) {
+        // drop the connection, next request will reconnect
+        if let Some(mut ctx) = self.ctx.take() {
+            let _cls = ctx.disconnect().await;
+        }
+    }
+
+    async fn connect(&mut self) -> Option<Context> {
+        // try to connect with an exponential backoff, without holding
+        // the bus while waiting so other watchers' requests fail fast
+        if Instant::now() < self.retry_at {
+            return None;
+        }
+
+        match self.transport.connect().await {
+            Ok(ctx) => {
+                info!("{} connected", &self.key);
+                self.backoff = MIN_BACKOFF;
+                Some(ctx)
+            }
+            Err(e) => {
+                error!(
+                    "{} unable to connect: {}, retrying in {:?}",
+                    &self.key, e, self.backoff
+                );
+                self.retry_at = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                None
+            }
+        }
+    }
+}
+
+pub async fn get_bus(
+    key: &str,
+    transport: Transport,
+    settings: String,
+    frame_delay: u64,
+) -> Arc<Mutex<Bus>> {
+    // get a shared bus, the first watcher sets transport's settings
+    let bus = BUSES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_insert_with(|| {
+            Arc::new(Mutex::new(Bus {
+                key: key.to_string(),
+                transport,
+                settings: settings.clone(),
+                ctx: None,
+                frame_delay,
+                frame_gap: Duration::ZERO,
+                last_frame: Instant::now(),
+                backoff: MIN_BACKOFF,
+                retry_at: Instant::now(),
+            }))
+        })
+        .clone();
+
+    // use the largest inter-frame gap among bus's watchers
+    {
+        let mut bus = bus.lock().await;
+
+        if bus.settings != settings {
+            warn!(
+                "{} already opened with {}, ignoring {}",
+                key, &bus.settings, settings
+            );
+        }
+
+        if bus.frame_delay != frame_delay {
+            warn!(
+                "{} frame delays differ ({}ms and {}ms), using the largest",
+                key, bus.frame_delay, frame_delay
+            );
+        }
+
+        bus.frame_gap = bus.frame_gap.max(Duration::from_millis(frame_delay));
+    }
+
+    bus
+}
+
+pub struct Block {
+    pub register: &'static str,
+    pub address: u16,
+    pub count: u16,
+    pub sensors: Vec<Sensor>,
+}
+
+pub fn plan(slave: &Slave) -> Vec<Block> {
+    // group slave's sensors into contiguous register ranges
+    let mut sensors = slave.sensors.clone();
+    sensors.sort_by_key(|sensor| (register_kind(&sensor.register), sensor.address));
+
+    let mut blocks: Vec<Block> = vec![];
+
+    for sensor in sensors {
+        if let Err(e) = validate(&sensor) {
+            error!("{}: skipped, {}", &sensor.name, e);
+            continue;
+        }
+
+        let register = register_kind(&sensor.register);
+        let max_count = match is_bit(register) {
+            true => MAX_BITS,
+            false => MAX_REGISTERS,
+        };
+        let end = sensor.address as u32 + register_count(&sensor) as u32;
+
+        match blocks.last_mut() {
+            // extend the current block when the sensor is adjacent or overlapping
+            Some(block)
+                if block.register == register
+                    && sensor.address as u32 <= block.address as u32 + block.count as u32
+                    && end - block.address as u32 <= max_count =>
+            {
+                block.count = block.count.max((end - block.address as u32) as u16);
+                block.sensors.push(sensor);
+            }
+
+            _ => blocks.push(Block {
+                register,
+                address: sensor.address,
+                count: (end - sensor.address as u32) as u16,
+                sensors: vec![sensor],
+            }),
+        }
+    }
+
+    blocks
+}
+
+pub fn plan_slaves(watcher: &Watcher) -> Vec<(&Slave, Vec<Block>)> {
+    // plan register blocks for each slave
+    let mut keys = HashMap::new();
+
+    for slave in &watcher.slaves {
+        for sensor in &slave.sensors {
+            let key = format!("{}/{}", slave.device_name(watcher), &sensor.name);
+
+            if let Some(address) = keys.insert(key.clone(), slave.address) {
+                warn!(
+                    "{} slaves {} and {} both publish {}, give them a name",
+                    &watcher.name, address, slave.address, &key
+                );
+            }
+        }
+    }
+
+    watcher
+        .slaves
+        .iter()
+        .map(|slave| (slave, plan(slave)))
+        .collect()
+}
+
+pub async fn poll(
+    bus: &Mutex<Bus>,
+    watcher: &Watcher,
+    slaves: &[(&Slave, Vec<Block>)],
+    tx: &mpsc::Sender<SensorUpdate>,
+    commands: &mut broadcast::Receiver<SensorCommand>,
+) {
+    let devices = watcher.devices();
+
+    loop {
+        for (slave, blocks) in slaves {
+            read_slave(bus, watcher, slave, blocks, tx).await;
+        }
+
+        // sleep for next polling cycle, handling write commands meanwhile
+        let next_cycle = sleep(Duration::from_millis(watcher.cycle_interval));
+        tokio::pin!(next_cycle);
+
+        loop {
+            tokio::select! {
+                _ = &mut next_cycle => break,
+
+                command = commands.recv() => match command {
+                    Ok(command) if devices.contains(&command.device_name) => {
+                        if command.issued.elapsed() > COMMAND_TIMEOUT {
+                            warn!(
+                                "{} {}: {:?} dropped, issued {:?} ago",
+                                &watcher.name,
+                                &command.sensor_name,
+                                &command.value,
+                                command.issued.elapsed()
+                            );
+                            continue;
+                        }
+
+                        write(bus, watcher, &command).await;
+
+                        // read back written values
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        warn!("{} dropped {} write commands", &watcher.name, count);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        next_cycle.as_mut().await;
+                        break;
+                    }
+                },
+            }
+        }
+    }
+}
+
+pub async fn write(bus: &Mutex<Bus>, watcher: &Watcher, command: &SensorCommand) {
+    // find the sensor owning the command
+    let Some((slave, sensor)) = watcher
+        .slaves
+        .iter()
+        .filter(|slave| slave.device_name(watcher) == command.device_name)
+        .flat_map(|slave| slave.sensors.iter().map(move |sensor| (slave, sensor)))
+        .find(|(_, sensor)| sensor.name == command.sensor_name)
+    else {
+        warn!(
+            "{} {} unknown sensor",
+            &command.device_name, &command.sensor_name
+        );
+        return;
+    };
+
+    if !sensor.writable {
+        warn!("{} {} is not writable", &watcher.name, &sensor.name);
+        return;
+    }
+
+    // Encode sensor value
+    let words = match register_kind(&sensor.register) {
+        "coil" => vec![],
+        "holding" => match encode(sensor, &command.value) {
+            Some(words) => words,
+            None => {
+                error!(
+                    "{} {} unable to encode {:?} as {}",
+                    &watcher.name, &sensor.name, &command.value, &sensor.data_type
+                );
+                return;
+            }
+        },
+        register => {
+            error!(
+                "{} {} {} registers are read only",
+                &watcher.name, &sensor.name, register
+            );
+            return;
+        }
+    };
+
+    let coil = match &command.value {
+        SensorValue::IsBool(value) => *value,
+        SensorValue::IsF64(value) => *value != 0.0,
+        _ => false,
+    };
+
+    // Write sensor value to modbus, waiting for our turn on the bus
+    let response = {
+        let mut bus = bus.lock().await;
+        let Some(ctx) = bus.context(slave.address).await else {
+            error!(
+                "{} {}: {} unavailable",
+                &watcher.name, &sensor.name, &bus.key
+            );
+            return;
+        };
+
+        let response = match words.as_slice() {
+            [] => timeout(TIMEOUT, ctx.write_single_coil(sensor.address, coil)).await,
+            [word] => timeout(TIMEOUT, ctx.write_single_register(sensor.address, *word)).await,
+            words => timeout(TIMEOUT, ctx.write_multiple_registers(sensor.address, words)).await,
+        };
+        bus.done();
+
+        if !matches!(response, Ok(Ok(_))) {
+            // transport error or timeout, a late reply would corrupt the next one
+            bus.reset().await;
+        }
+
+        response
+    };
+
+    match response {
+        Ok(Ok(Ok(()))) => info!(
+            "{} {}: {:?} written to modbus register {}",
+            &watcher.name, &sensor.name, &command.value, &sensor.address
+        ),
+
+        // modbus exception
+        Ok(Ok(Err(e))) => error!(
+            "{} {} error writing modbus register: {}",
+            &watcher.name, &sensor.name, &e
+        ),
+
+        // transport error
+        Ok(Err(e)) => error!(
+            "{} {} error writing modbus register: {}",
+            &watcher.name, &sensor.name, &e
+        ),
+
+        // modbus timeout
+        Err(e) => error!(
+            "{} {} timeout writing modbus register: {}",
+            &watcher.name, &sensor.name, &e
+        ),
+    }
+}
+
+pub async fn read_slave(
+    bus: &Mutex<Bus>,
+    watcher: &Watcher,
+    slave: &Slave,
+    blocks: &[Block],
+    tx: &mpsc::Sender<SensorUpdate>,
+) {
+    let device_name = slave.device_name(watcher);
+
+    for block in blocks {
+        // Read a whole block of registers from modbus, waiting for our turn on the bus
+        let response = {
+            let mut bus = bus.lock().await;
+
+            // bus down, skip this slave until reconnected
+            let Some(ctx) = bus.context(slave.address).await else {
+                return;
+            };
+
+            let response = timeout(
+                TIMEOUT,
+                read(ctx, block.register, block.address, block.count),
+            )
+            .await;
+            bus.done();
+
+            if !matches!(response, Ok(Ok(_))) {
+                // transport error or timeout, a late reply would corrupt the next one
+                bus.reset().await;
+            }
+
+            response
+        };
+
+        // prevent issues with serial, outside of the bus lock
+        sleep(Duration::from_millis(watcher.scan_interval)).await;
+
+        let registers = match response {
+            Ok(Ok(Ok(rsp))) => rsp,
+
+            Ok(Ok(Err(e))) => {
+                // modbus exception
+                error!(
+                    "{} error reading modbus registers {}-{}: {}",
+                    &watcher.name,
+                    &block.address,
+                    block.address as u32 + block.count as u32 - 1,
+                    &e
+                );
+                continue;
+            }
+
+            Ok(Err(e)) => {
+                // transport error
+                error!(
+                    "{} error reading modbus registers {}-{}: {}",
+                    &watcher.name,
+                    &block.address,
+                    block.address as u32 + block.count as u32 - 1,
+                    &e
+                );
+                continue;
+            }
+
+            Err(e) => {
+                // modbus timeout
+                error!(
+                    "{} timeout reading modbus registers {}-{}: {}",
+                    &watcher.name,
+                    &block.address,
+                    block.address as u32 + block.count as u32 - 1,
+                    &e
+                );
+                continue;
+            }
+        };
+
+        for sensor in &block.sensors {
+            // Decode sensor's registers according to its data type
+            let sensor_value = match registers
+                .slice(
+                    (sensor.address - block.address) as usize,
+                    register_count(sensor) as usize,
+                )
+                .and_then(|rsp| decode(sensor, &rsp))
+            {
+                Some(value) => value,
+                None => {
+                    error!(
+                        "{} {} unable to decode register {} as {}",
+                        &watcher.name, &sensor.name, &sensor.address, &sensor.data_type
+                    );
+                    continue;
+                }
+            };
+
+            trace!(
+                "{} {} => {:?}{}",
+                &watcher.name,
+                &sensor.name,
+                &sensor_value,
+                &sensor.unit
+            );
+
+            // Send data to HA
+            update_sensor(tx, &watcher.platform, &device_name, sensor, sensor_value).await;
+        }
+    }
+}
+
+pub async fn read(
+    ctx: &mut Context,
+    register: &str,
+    address: u16,
+    count: u16,
+) -> tokio_modbus::Result<Registers> {
+    // read registers of the given kind, holding registers by default
+    Ok(match register {
+        "input" => ctx
+            .read_input_registers(address, count)
+            .await?
+            .map(Registers::Words),
+        "coil" => ctx.read_coils(address, count).await?.map(Registers::Bits),
+        "discrete_input" => ctx
+            .read_discrete_inputs(address, count)
+            .await?
+            .map(Registers::Bits),
+        _ => ctx
+            .read_holding_registers(address, count)
+            .await?
+            .map(Registers::Words),
+    })
+}
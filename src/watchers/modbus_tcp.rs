@@ -0,0 +1,48 @@
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::tcp;
+
+use crate::watchers::modbus;
+use crate::{SensorCommand, SensorUpdate, Watcher};
+
+// default modbus tcp port
+const DEFAULT_PORT: u16 = 502;
+
+pub async fn run(
+    watcher: Watcher,
+    tx: mpsc::Sender<SensorUpdate>,
+    mut commands: broadcast::Receiver<SensorCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let port = match watcher.port {
+        0 => DEFAULT_PORT,
+        port => port,
+    };
+
+    // Share the connection with watchers using the same gateway
+    let bus = modbus::get_bus(
+        &format!("{}:{}", &watcher.host, port),
+        modbus::Transport::Tcp(watcher.host.clone(), port),
+        String::new(),
+        watcher.frame_delay,
+    )
+    .await;
+
+    // Keep polling slaves, reconnecting when the connection breaks
+    let slaves = modbus::plan_slaves(&watcher);
+    modbus::poll(&bus, &watcher, &slaves, &tx, &mut commands).await;
+
+    Ok(())
+}
+
+pub async fn connect(host: &str, port: u16) -> std::io::Result<Context> {
+    // resolve gateway address
+    let socket_addr: SocketAddr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "unable to resolve host"))?;
+
+    timeout(modbus::TIMEOUT, tcp::connect(socket_addr)).await?
+}
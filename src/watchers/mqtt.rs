@@ -0,0 +1,120 @@
+use log::{error, info, trace, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+
+use crate::{update_sensor, Sensor, SensorUpdate, SensorValue, Watcher};
+
+// default mqtt broker port
+const DEFAULT_PORT: u16 = 1883;
+
+// delay between reconnection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub async fn run(
+    watcher: Watcher,
+    tx: mpsc::Sender<SensorUpdate>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let port = match watcher.port {
+        0 => DEFAULT_PORT,
+        port => port,
+    };
+
+    // connect to mqtt broker
+    let mut mqttoptions =
+        MqttOptions::new(format!("{}-watcher", &watcher.name), &watcher.host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(30));
+
+    if !watcher.username.is_empty() {
+        mqttoptions.set_credentials(&watcher.username, &watcher.password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+    loop {
+        match eventloop.poll().await {
+            // subscribe to sensors' topics after each (re)connection
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("{} connected to {}:{}", &watcher.name, &watcher.host, port);
+
+                for sensor in &watcher.sensors {
+                    if let Err(e) = client.try_subscribe(&sensor.topic, QoS::AtLeastOnce) {
+                        error!(
+                            "{} unable to subscribe to {}: {}",
+                            &watcher.name, &sensor.topic, e
+                        );
+                    }
+                }
+            }
+
+            // map incoming messages to sensors
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let payload = String::from_utf8_lossy(&publish.payload);
+                trace!("{} {} => {}", &watcher.name, &publish.topic, &payload);
+
+                for sensor in watcher
+                    .sensors
+                    .iter()
+                    .filter(|sensor| rumqttc::matches(&publish.topic, &sensor.topic))
+                {
+                    match get_value(sensor, &payload) {
+                        Some(value) => {
+                            update_sensor(&tx, &watcher.platform, &watcher.name, sensor, value)
+                                .await
+                        }
+                        None => warn!(
+                            "{} {} no value at {} in {}",
+                            &watcher.name, &sensor.name, &sensor.value_path, &payload
+                        ),
+                    }
+                }
+            }
+
+            Ok(_) => {}
+
+            // the eventloop reconnects on next poll
+            Err(e) => {
+                error!(
+                    "{} connection to {}:{} failed: {}, retrying in {:?}",
+                    &watcher.name, &watcher.host, port, e, RECONNECT_DELAY
+                );
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+fn get_value(sensor: &Sensor, payload: &str) -> Option<SensorValue> {
+    // raw payloads without a value path
+    if sensor.value_path.is_empty() {
+        return Some(scale(sensor, SensorValue::from_payload(payload)));
+    }
+
+    // json pointer (/a/b/0) or dotted path (a.b.0)
+    let pointer = match sensor.value_path.starts_with('/') {
+        true => sensor.value_path.clone(),
+        false => format!("/{}", sensor.value_path.replace('.', "/")),
+    };
+
+    let json: serde_json::Value = serde_json::from_str(payload).ok()?;
+
+    let value = match json.pointer(&pointer)? {
+        serde_json::Value::Bool(value) => SensorValue::IsBool(*value),
+        serde_json::Value::Number(value) => SensorValue::IsF64(value.as_f64()?),
+        serde_json::Value::String(value) => SensorValue::from_payload(value),
+        serde_json::Value::Null => return None,
+        value => SensorValue::IsString(value.to_string()),
+    };
+
+    Some(scale(sensor, value))
+}
+
+fn scale(sensor: &Sensor, value: SensorValue) -> SensorValue {
+    // apply sensor's accuracy and offset to numeric values
+    match value {
+        SensorValue::IsF64(value) => {
+            SensorValue::IsF64(((value * sensor.accuracy + sensor.offset) * 100.0).round() / 100.0)
+        }
+        value => value,
+    }
+}
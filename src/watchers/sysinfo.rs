@@ -27,12 +27,14 @@ pub async fn run(
             state_class: "".to_string(),
             device_class: "duration".to_string(),
             debounce_delay: 0,
+            ..Default::default()
         };
 
         trace!("uptime => {}", &uptime_seconds);
 
         update_sensor(
             &tx,
+            &watcher.platform,
             &watcher.name,
             &uptime_sensor,
             SensorValue::IsF64(uptime_seconds),
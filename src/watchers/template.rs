@@ -0,0 +1,558 @@
+use log::{debug, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::iter::Peekable;
+use std::str::Chars;
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc};
+
+use crate::stages::round;
+use crate::{update_sensor, Sensor, SensorUpdate, SensorValue, Watcher};
+
+pub async fn run(
+    watcher: Watcher,
+    tx: mpsc::Sender<SensorUpdate>,
+    mut states: broadcast::Receiver<SensorUpdate>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // parse every template once
+    let mut templates: Vec<(&Sensor, Template)> = Vec::new();
+
+    for sensor in &watcher.sensors {
+        let template = Template::parse(&sensor.template)
+            .map_err(|e| format!("{} {}: {}", &watcher.name, &sensor.name, e))?;
+        templates.push((sensor, template));
+    }
+
+    // latest value of every input
+    let mut values: HashMap<String, SensorValue> = HashMap::new();
+
+    loop {
+        let update = match states.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(count)) => {
+                warn!("{}: {} updates skipped", &watcher.name, count);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let key = format!("{}/{}", &update.device_name, &update.sensor.name);
+
+        if !templates
+            .iter()
+            .any(|(_, template)| template.inputs.contains(&key))
+        {
+            continue;
+        }
+
+        values.insert(key.clone(), update.value);
+
+        // evaluate templates depending on the changed input
+        for (sensor, template) in &templates {
+            if !template.inputs.contains(&key) {
+                continue;
+            }
+
+            match template.eval(&values, sensor.template_precision) {
+                Ok(value) => {
+                    trace!("{} {} => {:?}", &watcher.name, &sensor.name, &value);
+                    update_sensor(&tx, &watcher.platform, &watcher.name, sensor, value).await;
+                }
+                Err(e) => debug!("{} {}: {}", &watcher.name, &sensor.name, e),
+            }
+        }
+    }
+}
+
+pub struct Template {
+    expr: Expr,
+
+    // device/sensor keys referenced by the expression
+    inputs: HashSet<String>,
+}
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(template)?,
+            position: 0,
+        };
+
+        let expr = parser.ternary()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?}", token));
+        }
+
+        let mut inputs = HashSet::new();
+        expr.inputs(&mut inputs);
+
+        Ok(Self { expr, inputs })
+    }
+
+    pub fn eval(
+        &self,
+        values: &HashMap<String, SensorValue>,
+        precision: u8,
+    ) -> Result<SensorValue, String> {
+        // round to the configured number of digits
+        match self.expr.eval(values)? {
+            SensorValue::IsF64(value) => Ok(SensorValue::IsF64(round(value, precision))),
+            value => Ok(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Input(String),
+    Ident(String),
+    Op(&'static str),
+}
+
+fn tokenize(template: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+
+            // {device/sensor}
+            '{' => {
+                chars.next();
+                let key = take_until(&mut chars, '}').ok_or("unterminated {")?;
+                tokens.push(Token::Input(key.trim().to_string()));
+            }
+
+            '\'' | '"' => {
+                chars.next();
+                let text = take_until(&mut chars, c).ok_or(format!("unterminated {}", c))?;
+                tokens.push(Token::Text(text));
+            }
+
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_digit() && c != '.' {
+                        break;
+                    }
+                    number.push(c);
+                    chars.next();
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("invalid number {}", number))?;
+                tokens.push(Token::Number(number));
+            }
+
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_alphanumeric() && c != '_' {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push(match ident.as_str() {
+                    "true" | "on" => Token::Op("true"),
+                    "false" | "off" => Token::Op("false"),
+                    _ => Token::Ident(ident),
+                });
+            }
+
+            _ => {
+                chars.next();
+                let next = chars.peek().copied();
+
+                let op = match (c, next) {
+                    ('=', Some('=')) => "==",
+                    ('!', Some('=')) => "!=",
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('&', Some('&')) => "&&",
+                    ('|', Some('|')) => "||",
+                    ('+', _) => "+",
+                    ('-', _) => "-",
+                    ('*', _) => "*",
+                    ('/', _) => "/",
+                    ('%', _) => "%",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    ('!', _) => "!",
+                    ('?', _) => "?",
+                    (':', _) => ":",
+                    ('(', _) => "(",
+                    (')', _) => ")",
+                    (',', _) => ",",
+                    _ => return Err(format!("unexpected {}", c)),
+                };
+
+                if op.len() == 2 {
+                    chars.next();
+                }
+                tokens.push(Token::Op(op));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn take_until(chars: &mut Peekable<Chars>, end: char) -> Option<String> {
+    // consume up to the closing character, None when it is missing
+    let mut text = String::new();
+
+    for c in chars.by_ref() {
+        if c == end {
+            return Some(text);
+        }
+        text.push(c);
+    }
+
+    None
+}
+
+fn arity(function: &str) -> Option<(usize, usize)> {
+    // minimum and maximum arguments of known functions
+    match function {
+        "abs" | "floor" | "ceil" => Some((1, 1)),
+        "round" => Some((1, 2)),
+        "min" | "max" => Some((1, usize::MAX)),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Value(SensorValue),
+    Input(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+impl Expr {
+    fn inputs(&self, inputs: &mut HashSet<String>) {
+        match self {
+            Expr::Value(_) => {}
+            Expr::Input(key) => {
+                inputs.insert(key.clone());
+            }
+            Expr::Unary(_, expr) => expr.inputs(inputs),
+            Expr::Binary(_, left, right) => {
+                left.inputs(inputs);
+                right.inputs(inputs);
+            }
+            Expr::Ternary(condition, then, otherwise) => {
+                condition.inputs(inputs);
+                then.inputs(inputs);
+                otherwise.inputs(inputs);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.inputs(inputs)),
+        }
+    }
+
+    fn eval(&self, values: &HashMap<String, SensorValue>) -> Result<SensorValue, String> {
+        match self {
+            Expr::Value(value) => Ok(value.clone()),
+
+            Expr::Input(key) => match values.get(key) {
+                None | Some(SensorValue::None) => Err(format!("{} unavailable", key)),
+                Some(value) => Ok(value.clone()),
+            },
+
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(values)?;
+                match *op {
+                    "-" => Ok(SensorValue::IsF64(-number(&value)?)),
+                    _ => Ok(SensorValue::IsBool(!boolean(&value)?)),
+                }
+            }
+
+            // short circuit boolean operators
+            Expr::Binary("&&", left, right) => Ok(SensorValue::IsBool(
+                boolean(&left.eval(values)?)? && boolean(&right.eval(values)?)?,
+            )),
+            Expr::Binary("||", left, right) => Ok(SensorValue::IsBool(
+                boolean(&left.eval(values)?)? || boolean(&right.eval(values)?)?,
+            )),
+
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(values)?, right.eval(values)?);
+
+                match *op {
+                    "==" => return Ok(SensorValue::IsBool(left == right)),
+                    "!=" => return Ok(SensorValue::IsBool(left != right)),
+                    _ => {}
+                }
+
+                let (left, right) = (number(&left)?, number(&right)?);
+
+                Ok(match *op {
+                    "+" => SensorValue::IsF64(left + right),
+                    "-" => SensorValue::IsF64(left - right),
+                    "*" => SensorValue::IsF64(left * right),
+                    "/" | "%" if right == 0.0 => return Err("division by zero".to_string()),
+                    "/" => SensorValue::IsF64(left / right),
+                    "%" => SensorValue::IsF64(left % right),
+                    "<" => SensorValue::IsBool(left < right),
+                    "<=" => SensorValue::IsBool(left <= right),
+                    ">" => SensorValue::IsBool(left > right),
+                    _ => SensorValue::IsBool(left >= right),
+                })
+            }
+
+            Expr::Ternary(condition, then, otherwise) => match boolean(&condition.eval(values)?)? {
+                true => then.eval(values),
+                false => otherwise.eval(values),
+            },
+
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| number(&arg.eval(values)?))
+                    .collect::<Result<Vec<f64>, String>>()?;
+
+                let value = match (function.as_str(), args.as_slice()) {
+                    ("abs", [value]) => value.abs(),
+                    ("floor", [value]) => value.floor(),
+                    ("ceil", [value]) => value.ceil(),
+                    ("round", [value]) => value.round(),
+                    ("round", [value, digits]) => {
+                        let scale = 10f64.powi(*digits as i32);
+                        (value * scale).round() / scale
+                    }
+                    ("min", [first, rest @ ..]) => rest.iter().fold(*first, |a, b| a.min(*b)),
+                    ("max", [first, rest @ ..]) => rest.iter().fold(*first, |a, b| a.max(*b)),
+                    _ => return Err(format!("invalid call to {}", function)),
+                };
+
+                Ok(SensorValue::IsF64(value))
+            }
+        }
+    }
+}
+
+fn number(value: &SensorValue) -> Result<f64, String> {
+    // booleans count as 1 and 0
+    match value {
+        SensorValue::IsF64(value) => Ok(*value),
+        SensorValue::IsBool(value) => Ok(*value as u8 as f64),
+        _ => Err(format!("{:?} is not a number", value)),
+    }
+}
+
+fn boolean(value: &SensorValue) -> Result<bool, String> {
+    match value {
+        SensorValue::IsBool(value) => Ok(*value),
+        SensorValue::IsF64(value) => Ok(*value != 0.0),
+        _ => Err(format!("{:?} is not a boolean", value)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        // consume the next token if it is one of the operators
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.position += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<(), String> {
+        match self.accept(&[op]) {
+            Some(_) => Ok(()),
+            None => Err(format!("expected {}", op)),
+        }
+    }
+
+    fn ternary(&mut self) -> Result<Expr, String> {
+        let condition = self.binary(0)?;
+
+        if self.accept(&["?"]).is_none() {
+            return Ok(condition);
+        }
+
+        let then = self.ternary()?;
+        self.expect(":")?;
+        let otherwise = self.ternary()?;
+
+        Ok(Expr::Ternary(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        // operators by increasing precedence
+        const LEVELS: [&[&str]; 5] = [
+            &["||"],
+            &["&&"],
+            &["==", "!=", "<", "<=", ">", ">="],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+
+        while let Some(op) = self.accept(LEVELS[level]) {
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.accept(&["-", "!"]) {
+            Some(op) => Ok(Expr::Unary(op, Box::new(self.unary()?))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Value(SensorValue::IsF64(value))),
+            Some(Token::Text(text)) => Ok(Expr::Value(SensorValue::IsString(text))),
+            Some(Token::Input(key)) => Ok(Expr::Input(key)),
+            Some(Token::Op("true")) => Ok(Expr::Value(SensorValue::IsBool(true))),
+            Some(Token::Op("false")) => Ok(Expr::Value(SensorValue::IsBool(false))),
+
+            Some(Token::Op("(")) => {
+                let expr = self.ternary()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+
+            Some(Token::Ident(function)) => {
+                self.expect("(")?;
+
+                let mut args = Vec::new();
+                if self.accept(&[")"]).is_none() {
+                    loop {
+                        args.push(self.ternary()?);
+                        match self.accept(&[",", ")"]) {
+                            Some(",") => continue,
+                            Some(_) => break,
+                            None => return Err("expected , or )".to_string()),
+                        }
+                    }
+                }
+
+                // reject unknown functions when the config is loaded
+                match arity(&function) {
+                    None => Err(format!("unknown function {}", function)),
+                    Some((min, max)) if args.len() < min || args.len() > max => Err(format!(
+                        "invalid call to {} with {} arguments",
+                        function,
+                        args.len()
+                    )),
+                    Some(_) => Ok(Expr::Call(function, args)),
+                }
+            }
+
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of template".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(template: &str) -> Result<SensorValue, String> {
+        let values = HashMap::from([
+            ("meter/power".to_string(), SensorValue::IsF64(1500.0)),
+            ("meter/on".to_string(), SensorValue::IsBool(true)),
+        ]);
+
+        Template::parse(template)?.eval(&values, 4)
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(SensorValue::IsF64(7.0)));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(SensorValue::IsF64(9.0)));
+        assert_eq!(eval("10 - 4 - 3"), Ok(SensorValue::IsF64(3.0)));
+        assert_eq!(eval("-2 * 3 + 7 % 4"), Ok(SensorValue::IsF64(-3.0)));
+        assert_eq!(eval("1 + 2 == 3"), Ok(SensorValue::IsBool(true)));
+        assert_eq!(
+            eval("1 < 2 || 1 > 2 && false"),
+            Ok(SensorValue::IsBool(true))
+        );
+        assert_eq!(eval("!false && 2 >= 3"), Ok(SensorValue::IsBool(false)));
+        assert_eq!(
+            eval("1 > 2 ? 'a' : 2 > 1 ? 'b' : 'c'"),
+            Ok(SensorValue::IsString("b".to_string()))
+        );
+    }
+
+    #[test]
+    fn inputs_and_functions() {
+        assert_eq!(
+            eval("{meter/power} / 1000 * {meter/on}"),
+            Ok(SensorValue::IsF64(1.5))
+        );
+        assert_eq!(eval("round(1 / 3, 2)"), Ok(SensorValue::IsF64(0.33)));
+        assert_eq!(eval("1 / 3"), Ok(SensorValue::IsF64(0.3333)));
+        assert_eq!(
+            eval("max(abs(-4), min(9, 5), 2)"),
+            Ok(SensorValue::IsF64(5.0))
+        );
+        assert!(eval("{meter/missing} + 1").is_err());
+        assert!(eval("1 / 0").is_err());
+    }
+
+    #[test]
+    fn unterminated_inputs_and_strings_are_rejected() {
+        for template in [
+            "{meter/power + 1",
+            "'on",
+            "\"on",
+            "{meter/power} > 1 ? 'a : 'b'",
+        ] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn unknown_functions_are_rejected() {
+        for template in ["sqrt(4)", "round(1, 2, 3)", "abs()", "min()"] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        for template in ["", "1 +", "(1 + 2", "1 2", "1 # 2", "abs 1"] {
+            assert!(Template::parse(template).is_err(), "{}", template);
+        }
+    }
+}